@@ -0,0 +1,935 @@
+use std::{fmt::Debug, path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use poem::{
+    error::{BadRequest, Conflict, InternalServerError},
+    http::StatusCode,
+};
+use poem_openapi::{types::ParseFromParameter, Object};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    checksums::{Digests, Hashes},
+    ids::ArtifactName,
+    Config,
+};
+
+/// Prefix of files being received, which is never a valid artifact name.
+const STAGING_PREFIX: &str = ".upload-";
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ArtifactError {
+    #[error("Artifact `{0}` already exists")]
+    Exists(String),
+
+    #[error("Could not read upload: {0}")]
+    Body(#[source] std::io::Error),
+
+    #[error("Artifact is larger than {limit} bytes")]
+    TooLarge { limit: u64 },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Storage request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("Storage responded with status {0}")]
+    Status(reqwest::StatusCode),
+
+    #[error("Invalid storage endpoint `{0}`")]
+    Endpoint(String),
+}
+
+impl From<ArtifactError> for poem::Error {
+    fn from(e: ArtifactError) -> Self {
+        match e {
+            e @ ArtifactError::Exists(_) => Conflict(e),
+            e @ ArtifactError::Body(_) => BadRequest(e),
+            e @ ArtifactError::TooLarge { .. } => {
+                poem::Error::new(e, StatusCode::PAYLOAD_TOO_LARGE)
+            }
+            e => InternalServerError(e),
+        }
+    }
+}
+
+/// Somewhere uploaded artifacts are kept and served from.
+#[async_trait]
+pub(crate) trait ArtifactStore: Debug + Send + Sync {
+    /// Directory uploads are written to while they are received and hashed.
+    fn staging_dir(&self) -> PathBuf;
+
+    /// Whether an artifact named `file_name` is already stored.
+    async fn exists(&self, file_name: &str) -> Result<bool, ArtifactError>;
+
+    /// Moves a fully received upload into the store, refusing to replace an
+    /// existing artifact.
+    async fn store(
+        &self,
+        file_name: &str,
+        staged: NamedTempFile,
+        digests: &Digests,
+    ) -> Result<(), ArtifactError>;
+
+    /// The public URL of a stored artifact.
+    fn url(&self, file_name: &str) -> String;
+}
+
+/// Stores artifacts in `artifact_root`.
+#[derive(Debug)]
+pub(crate) struct LocalStore {
+    root: PathBuf,
+    base_url: String,
+}
+
+impl LocalStore {
+    pub fn new(root: PathBuf, base_url: String) -> Self {
+        Self { root, base_url }
+    }
+
+    pub fn path(&self, file_name: &str) -> PathBuf {
+        self.root.join(file_name)
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for LocalStore {
+    fn staging_dir(&self) -> PathBuf {
+        // Staging next to the artifacts keeps the final rename atomic.
+        self.root.clone()
+    }
+
+    async fn exists(&self, file_name: &str) -> Result<bool, ArtifactError> {
+        Ok(tokio::fs::try_exists(self.path(file_name)).await?)
+    }
+
+    async fn store(
+        &self,
+        file_name: &str,
+        staged: NamedTempFile,
+        _digests: &Digests,
+    ) -> Result<(), ArtifactError> {
+        let path = self.path(file_name);
+        let file_name = file_name.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(staged.path(), std::fs::Permissions::from_mode(0o644))?;
+            }
+
+            match staged.persist_noclobber(path) {
+                Ok(_) => Ok(()),
+                Err(e) if e.error.kind() == std::io::ErrorKind::AlreadyExists => {
+                    Err(ArtifactError::Exists(file_name))
+                }
+                Err(e) => Err(e.error.into()),
+            }
+        })
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+    }
+
+    fn url(&self, file_name: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), file_name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S3Config {
+    /// Base URL of the S3-compatible API, e.g. `https://s3.eu-north-1.amazonaws.com`
+    pub endpoint: String,
+
+    pub bucket: String,
+
+    /// Region used when signing requests (default: us-east-1)
+    #[serde(default = "default_region")]
+    pub region: String,
+
+    pub access_key_id: String,
+
+    pub secret_access_key: String,
+
+    /// Public URL prefix objects are downloaded from (default: `<endpoint>/<bucket>`)
+    #[serde(default)]
+    pub public_url: Option<String>,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// Stores artifacts in a bucket of an S3-compatible service, using path-style
+/// requests signed with AWS Signature Version 4.
+#[derive(Debug)]
+pub(crate) struct S3Store {
+    config: S3Config,
+    /// Scheme and authority of the endpoint
+    origin: String,
+    host: String,
+    /// Path prefix of the endpoint, without a trailing slash
+    base_path: String,
+    client: reqwest::Client,
+}
+
+/// SHA-256 of an empty request body.
+const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl S3Store {
+    pub fn new(config: S3Config) -> Result<Self, ArtifactError> {
+        let endpoint = reqwest::Url::parse(&config.endpoint)
+            .map_err(|_| ArtifactError::Endpoint(config.endpoint.clone()))?;
+        let host = match (endpoint.host_str(), endpoint.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(ArtifactError::Endpoint(config.endpoint.clone())),
+        };
+
+        // Uploads stream whole artifacts, so the overall timeout is generous.
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(30 * 60))
+            .build()?;
+
+        Ok(Self {
+            origin: format!("{}://{}", endpoint.scheme(), host),
+            base_path: endpoint.path().trim_end_matches('/').to_string(),
+            config,
+            host,
+            client,
+        })
+    }
+
+    fn object_path(&self, file_name: &str) -> String {
+        // Artifact names are validated to be plain ASCII without reserved
+        // characters, so they need no further encoding.
+        format!("{}/{}/{}", self.base_path, self.config.bucket, file_name)
+    }
+
+    fn object_url(&self, file_name: &str) -> String {
+        format!("{}{}", self.origin, self.object_path(file_name))
+    }
+
+    /// Builds a request carrying the `Authorization` and `x-amz-*` headers
+    /// for `method` on the object.
+    fn signed(
+        &self,
+        method: reqwest::Method,
+        file_name: &str,
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> reqwest::RequestBuilder {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method.as_str(),
+            self.object_path(file_name),
+            self.host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = format!("AWS4{}", self.config.secret_access_key);
+        let key = hmac_sha256(key.as_bytes(), &date);
+        let key = hmac_sha256(&key, &self.config.region);
+        let key = hmac_sha256(&key, "s3");
+        let key = hmac_sha256(&key, "aws4_request");
+        let signature = hex::encode(hmac_sha256(&key, &string_to_sign));
+
+        self.client
+            .request(method, self.object_url(file_name))
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.config.access_key_id, scope, signed_headers, signature
+                ),
+            )
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for S3Store {
+    fn staging_dir(&self) -> PathBuf {
+        std::env::temp_dir()
+    }
+
+    async fn exists(&self, file_name: &str) -> Result<bool, ArtifactError> {
+        let response = self
+            .signed(
+                reqwest::Method::HEAD,
+                file_name,
+                EMPTY_PAYLOAD_HASH,
+                Utc::now(),
+            )
+            .send()
+            .await?;
+
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(ArtifactError::Status(status)),
+        }
+    }
+
+    async fn store(
+        &self,
+        file_name: &str,
+        staged: NamedTempFile,
+        digests: &Digests,
+    ) -> Result<(), ArtifactError> {
+        let file = tokio::fs::File::open(staged.path()).await?;
+
+        let response = self
+            .signed(reqwest::Method::PUT, file_name, &digests.sha256, Utc::now())
+            .header(reqwest::header::CONTENT_LENGTH, digests.size)
+            .header(reqwest::header::IF_NONE_MATCH, "*")
+            .body(file)
+            .send()
+            .await?;
+
+        match response.status() {
+            reqwest::StatusCode::PRECONDITION_FAILED => {
+                Err(ArtifactError::Exists(file_name.to_string()))
+            }
+            status if status.is_success() => Ok(()),
+            status => Err(ArtifactError::Status(status)),
+        }
+    }
+
+    fn url(&self, file_name: &str) -> String {
+        match self.config.public_url.as_deref() {
+            Some(v) => format!("{}/{}", v.trim_end_matches('/'), file_name),
+            None => self.object_url(file_name),
+        }
+    }
+}
+
+/// Public URL prefix of artifacts in `artifact_root`, without a trailing slash.
+fn local_base_url(config: &Config) -> String {
+    match config.artifact_url.as_deref() {
+        Some(v) => v.trim_end_matches('/').to_string(),
+        None => format!("{}/artifacts", config.url.trim_end_matches('/')),
+    }
+}
+
+/// The configured artifact store: `artifact_s3` if set, otherwise
+/// `artifact_root`, otherwise none and uploads are disabled.
+pub(crate) fn from_config(
+    config: &Config,
+) -> Result<Option<Box<dyn ArtifactStore>>, ArtifactError> {
+    if let Some(s3) = config.artifact_s3.clone() {
+        return Ok(Some(Box::new(S3Store::new(s3)?)));
+    }
+
+    Ok(config.artifact_root.clone().map(|root| {
+        Box::new(LocalStore::new(root, local_base_url(config))) as Box<dyn ArtifactStore>
+    }))
+}
+
+/// The file in `artifact_root` named `file_name`, if there is one.
+pub(crate) fn local_path(config: &Config, file_name: &str) -> Option<PathBuf> {
+    config
+        .artifact_root
+        .as_ref()
+        .map(|root| root.join(file_name))
+        .filter(|path| path.is_file())
+}
+
+/// The artifact name in a payload URL that points at this server's
+/// `artifact_url`, so it can be served from `artifact_root`.
+pub(crate) fn hosted_file_name<'a>(config: &Config, url: &'a str) -> Option<&'a str> {
+    config.artifact_root.as_ref()?;
+    let base_url = local_base_url(config);
+    let file_name = url.strip_prefix(base_url.as_str())?.strip_prefix('/')?;
+    ArtifactName::parse_from_parameter(file_name).ok()?;
+    Some(file_name)
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Range not satisfiable")]
+pub(crate) struct RangeNotSatisfiable;
+
+/// A single, inclusive byte range of an artifact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Parses a `Range` header for a file of `size` bytes.
+    ///
+    /// `Ok(None)` means the header is ignored and the whole file is served,
+    /// which is also what happens for multiple ranges.
+    pub fn parse(value: &str, size: u64) -> Result<Option<Self>, RangeNotSatisfiable> {
+        let spec = match value.trim().strip_prefix("bytes=") {
+            Some(v) if !v.contains(',') => v,
+            _ => return Ok(None),
+        };
+        let (start, end) = match spec.split_once('-') {
+            Some((start, end)) => (start.trim(), end.trim()),
+            None => return Ok(None),
+        };
+
+        if start.is_empty() {
+            let suffix = match end.parse::<u64>() {
+                Ok(v) => v,
+                Err(_) => return Ok(None),
+            };
+            if suffix == 0 || size == 0 {
+                return Err(RangeNotSatisfiable);
+            }
+            return Ok(Some(ByteRange {
+                start: size.saturating_sub(suffix),
+                end: size - 1,
+            }));
+        }
+
+        let start = match start.parse::<u64>() {
+            Ok(v) => v,
+            Err(_) => return Ok(None),
+        };
+        if start >= size {
+            return Err(RangeNotSatisfiable);
+        }
+        let end = if end.is_empty() {
+            size - 1
+        } else {
+            match end.parse::<u64>() {
+                Ok(v) if v >= start => v.min(size - 1),
+                _ => return Ok(None),
+            }
+        };
+
+        Ok(Some(ByteRange { start, end }))
+    }
+}
+
+/// Whether a `Range` header asks for anything but the start of the file,
+/// i.e. the client is resuming a download it already counted.
+///
+/// Headers that `ByteRange::parse` ignores are not resumptions, since the
+/// whole file is served for them.
+pub(crate) fn resumes_download(range: &str) -> bool {
+    let spec = match range.trim().strip_prefix("bytes=") {
+        Some(v) if !v.contains(',') => v,
+        _ => return false,
+    };
+
+    match spec.split_once('-').map(|(start, _)| start.trim()) {
+        // A suffix range only asks for the end of the file.
+        Some("") => true,
+        Some(start) => start.parse::<u64>().map(|x| x > 0).unwrap_or(false),
+        None => false,
+    }
+}
+
+/// A stored artifact, with what a package update needs to reference it.
+#[derive(Debug, Clone, Object)]
+pub(crate) struct UploadedArtifact {
+    file_name: String,
+    /// URL to use as `target.payload.url`
+    url: String,
+    /// Size in bytes, to use as `target.payload.size`
+    size: u64,
+    /// Hex encoded SHA-256
+    sha256: String,
+    /// Hex encoded BLAKE3
+    blake3: String,
+}
+
+/// Streams `body` into a staging file while hashing it, then stores it as
+/// `file_name`.
+///
+/// Uploads declaring or sending more than `limit` bytes are rejected, and
+/// the partial staging file is removed.
+pub(crate) async fn upload(
+    store: &dyn ArtifactStore,
+    file_name: &str,
+    content_length: Option<u64>,
+    body: poem::Body,
+    limit: u64,
+) -> Result<UploadedArtifact, ArtifactError> {
+    if content_length.map(|x| x > limit).unwrap_or(false) {
+        return Err(ArtifactError::TooLarge { limit });
+    }
+
+    if store.exists(file_name).await? {
+        return Err(ArtifactError::Exists(file_name.to_string()));
+    }
+
+    let staging_dir = store.staging_dir();
+    let staged = tokio::task::spawn_blocking(move || {
+        tempfile::Builder::new()
+            .prefix(STAGING_PREFIX)
+            .tempfile_in(staging_dir)
+    })
+    .await
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??;
+
+    let mut file = tokio::fs::File::from_std(staged.as_file().try_clone()?);
+    let mut body = body.into_async_read();
+    let mut hashes = Hashes::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = body.read(&mut buf).await.map_err(ArtifactError::Body)?;
+        if n == 0 {
+            break;
+        }
+        hashes.update(&buf[..n]);
+        if hashes.size() > limit {
+            return Err(ArtifactError::TooLarge { limit });
+        }
+        file.write_all(&buf[..n]).await?;
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+    drop(file);
+
+    let digests = hashes.finish();
+    store.store(file_name, staged, &digests).await?;
+    tracing::info!(
+        "Stored artifact {} ({} bytes, sha256 {})",
+        file_name,
+        digests.size,
+        digests.sha256
+    );
+
+    Ok(UploadedArtifact {
+        file_name: file_name.to_string(),
+        url: store.url(file_name),
+        size: digests.size,
+        sha256: digests.sha256,
+        blake3: digests.blake3,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Write, sync::Arc};
+
+    use parking_lot::Mutex;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// SHA-256 of `hello`.
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    struct Received {
+        method: String,
+        path: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    async fn read_request(socket: &mut TcpStream) -> Received {
+        let mut buf = vec![];
+        let mut chunk = [0u8; 1024];
+
+        let head_end = loop {
+            let n = socket.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the headers were read");
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+
+        let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+        let mut lines = head.lines();
+        let mut request_line = lines.next().unwrap().split(' ');
+        let method = request_line.next().unwrap().to_string();
+        let path = request_line.next().unwrap().to_string();
+        let headers: HashMap<String, String> = lines
+            .filter_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                Some((key.trim().to_ascii_lowercase(), value.trim().to_string()))
+            })
+            .collect();
+        let len: usize = headers
+            .get("content-length")
+            .map(|x| x.parse().unwrap())
+            .unwrap_or(0);
+
+        while buf.len() < head_end + len {
+            let n = socket.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the body was read");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+
+        Received {
+            method,
+            path,
+            headers,
+            body: buf[head_end..head_end + len].to_vec(),
+        }
+    }
+
+    /// A stand-in for an S3 endpoint that answers each request with the next
+    /// status in `statuses`.
+    async fn s3_stand_in(statuses: Vec<u16>) -> (S3Store, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(vec![]));
+        let log = received.clone();
+
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut socket).await;
+                log.lock().push(request);
+
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                tokio::io::AsyncWriteExt::write_all(&mut socket, response.as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (S3Store::new(s3_config(&endpoint)).unwrap(), received)
+    }
+
+    fn s3_config(endpoint: &str) -> S3Config {
+        S3Config {
+            endpoint: endpoint.to_string(),
+            bucket: "artifacts".to_string(),
+            region: "eu-north-1".to_string(),
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            public_url: None,
+        }
+    }
+
+    fn staged(dir: &std::path::Path, contents: &[u8]) -> NamedTempFile {
+        let mut file = tempfile::Builder::new()
+            .prefix(STAGING_PREFIX)
+            .tempfile_in(dir)
+            .unwrap();
+        file.write_all(contents).unwrap();
+        file
+    }
+
+    fn hello_digests() -> Digests {
+        let mut hashes = Hashes::new();
+        hashes.update(b"hello");
+        hashes.finish()
+    }
+
+    // Expected signatures were computed independently with botocore's
+    // `S3SigV4Auth` for the same request.
+    #[test]
+    fn signed_requests_match_reference_signatures() {
+        let store = S3Store::new(s3_config("http://127.0.0.1:9000/s3")).unwrap();
+        let now = "2024-01-08T00:18:17Z".parse::<DateTime<Utc>>().unwrap();
+
+        let cases = [
+            (
+                reqwest::Method::HEAD,
+                EMPTY_PAYLOAD_HASH,
+                "af951bba0f460ccb643c1be93b37a526362ce98e0e5580d7f873ff89d6837a8f",
+            ),
+            (
+                reqwest::Method::PUT,
+                HELLO_SHA256,
+                "0b2f00abf75a51002f3cbec352a591846264430318632a2c91ffe07598f20eb6",
+            ),
+        ];
+
+        for (method, payload_hash, signature) in cases {
+            let request = store
+                .signed(method, "speller-sme_1.0.0.pkg", payload_hash, now)
+                .build()
+                .unwrap();
+
+            assert_eq!(
+                request.url().as_str(),
+                "http://127.0.0.1:9000/s3/artifacts/speller-sme_1.0.0.pkg"
+            );
+            assert_eq!(request.headers()["x-amz-date"], "20240108T001817Z");
+            assert_eq!(request.headers()["x-amz-content-sha256"], payload_hash);
+            assert_eq!(
+                request.headers()[reqwest::header::AUTHORIZATION],
+                format!(
+                    "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20240108/eu-north-1/s3/aws4_request, \
+                     SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                    signature
+                )
+                .as_str()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn s3_exists_maps_statuses() {
+        let (store, received) = s3_stand_in(vec![404, 200, 403]).await;
+
+        assert!(!store.exists("speller.pkg").await.unwrap());
+        assert!(store.exists("speller.pkg").await.unwrap());
+        assert!(matches!(
+            store.exists("speller.pkg").await,
+            Err(ArtifactError::Status(s)) if s.as_u16() == 403
+        ));
+
+        let received = received.lock();
+        assert!(received.iter().all(|x| x.method == "HEAD"));
+        assert_eq!(received[0].path, "/artifacts/speller.pkg");
+    }
+
+    #[tokio::test]
+    async fn s3_store_puts_signed_body_without_clobbering() {
+        let (store, received) = s3_stand_in(vec![200]).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        store
+            .store(
+                "speller.pkg",
+                staged(dir.path(), b"hello"),
+                &hello_digests(),
+            )
+            .await
+            .unwrap();
+
+        let received = received.lock();
+        let request = &received[0];
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/artifacts/speller.pkg");
+        assert_eq!(request.body, b"hello");
+        assert_eq!(request.headers["if-none-match"], "*");
+        assert_eq!(request.headers["x-amz-content-sha256"], HELLO_SHA256);
+        assert!(request.headers["authorization"].starts_with("AWS4-HMAC-SHA256 "));
+    }
+
+    #[tokio::test]
+    async fn s3_precondition_failed_means_exists() {
+        let (store, _) = s3_stand_in(vec![412]).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = store
+            .store(
+                "speller.pkg",
+                staged(dir.path(), b"hello"),
+                &hello_digests(),
+            )
+            .await;
+
+        assert!(matches!(result, Err(ArtifactError::Exists(name)) if name == "speller.pkg"));
+    }
+
+    #[tokio::test]
+    async fn local_store_keeps_the_first_of_racing_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path().to_path_buf(), "https://pahkat.example".into());
+        let digests = hello_digests();
+
+        let (a, b) = tokio::join!(
+            store.store("speller.pkg", staged(dir.path(), b"first"), &digests),
+            store.store("speller.pkg", staged(dir.path(), b"second"), &digests),
+        );
+
+        let contents = std::fs::read(store.path("speller.pkg")).unwrap();
+        match (a, b) {
+            (Ok(()), Err(ArtifactError::Exists(_))) => assert_eq!(contents, b"first"),
+            (Err(ArtifactError::Exists(_)), Ok(())) => assert_eq!(contents, b"second"),
+            (a, b) => panic!("expected exactly one upload to win: {:?}, {:?}", a, b),
+        }
+
+        // Only the stored artifact is left; the losing staging file is gone.
+        let names = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![std::ffi::OsString::from("speller.pkg")]);
+    }
+
+    #[tokio::test]
+    async fn local_store_never_replaces_an_artifact() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path().to_path_buf(), "https://pahkat.example".into());
+        std::fs::write(store.path("speller.pkg"), b"original").unwrap();
+
+        let result = store
+            .store(
+                "speller.pkg",
+                staged(dir.path(), b"hello"),
+                &hello_digests(),
+            )
+            .await;
+
+        assert!(matches!(result, Err(ArtifactError::Exists(_))));
+        assert_eq!(
+            std::fs::read(store.path("speller.pkg")).unwrap(),
+            b"original"
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_rejects_declared_oversize_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path().to_path_buf(), "https://pahkat.example".into());
+
+        let result = upload(
+            &store,
+            "speller.pkg",
+            Some(6),
+            poem::Body::from_vec(b"hello!".to_vec()),
+            5,
+        )
+        .await;
+
+        assert!(matches!(result, Err(ArtifactError::TooLarge { limit: 5 })));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn upload_stops_reading_past_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path().to_path_buf(), "https://pahkat.example".into());
+
+        let result = upload(
+            &store,
+            "speller.pkg",
+            None,
+            poem::Body::from_vec(vec![0u8; 100 * 1024]),
+            64 * 1024,
+        )
+        .await;
+
+        assert!(matches!(result, Err(ArtifactError::TooLarge { .. })));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    fn range(start: u64, end: u64) -> Option<ByteRange> {
+        Some(ByteRange { start, end })
+    }
+
+    #[test]
+    fn byte_range_parses_bounded_and_open_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-499", 1000).unwrap(),
+            range(0, 499)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=500-", 1000).unwrap(),
+            range(500, 999)
+        );
+        assert_eq!(
+            ByteRange::parse(" bytes=10-10 ", 1000).unwrap(),
+            range(10, 10)
+        );
+        assert_eq!(range(10, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn byte_range_parses_suffix_ranges() {
+        assert_eq!(
+            ByteRange::parse("bytes=-500", 1000).unwrap(),
+            range(500, 999)
+        );
+        // A suffix longer than the file selects all of it.
+        assert_eq!(
+            ByteRange::parse("bytes=-5000", 1000).unwrap(),
+            range(0, 999)
+        );
+        assert!(ByteRange::parse("bytes=-0", 1000).is_err());
+        assert!(ByteRange::parse("bytes=-10", 0).is_err());
+    }
+
+    #[test]
+    fn byte_range_end_past_eof_is_clamped() {
+        assert_eq!(
+            ByteRange::parse("bytes=900-5000", 1000).unwrap(),
+            range(900, 999)
+        );
+    }
+
+    #[test]
+    fn byte_range_start_past_eof_is_unsatisfiable() {
+        assert!(ByteRange::parse("bytes=1000-", 1000).is_err());
+        assert!(ByteRange::parse("bytes=5000-6000", 1000).is_err());
+        assert!(ByteRange::parse("bytes=0-", 0).is_err());
+    }
+
+    #[test]
+    fn byte_range_falls_back_to_the_whole_file() {
+        // Multiple ranges
+        assert_eq!(ByteRange::parse("bytes=0-1,5-9", 1000).unwrap(), None);
+        // Other units, and malformed or inverted ranges
+        assert_eq!(ByteRange::parse("items=0-1", 1000).unwrap(), None);
+        assert_eq!(ByteRange::parse("bytes=abc-", 1000).unwrap(), None);
+        assert_eq!(ByteRange::parse("bytes=10", 1000).unwrap(), None);
+        assert_eq!(ByteRange::parse("bytes=500-100", 1000).unwrap(), None);
+    }
+
+    #[test]
+    fn ranges_from_the_start_are_not_resumptions() {
+        assert!(!resumes_download("bytes=0-"));
+        assert!(!resumes_download("bytes=0-499"));
+        assert!(!resumes_download(" bytes= 0 -1023"));
+        assert!(!resumes_download("bytes=0-1,100-200"));
+        assert!(!resumes_download("bytes=nonsense"));
+
+        assert!(resumes_download("bytes=500-"));
+        assert!(resumes_download("bytes=1-499"));
+        assert!(resumes_download("bytes=-500"));
+    }
+
+    #[tokio::test]
+    async fn upload_stores_and_hashes_the_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore::new(dir.path().to_path_buf(), "https://pahkat.example".into());
+
+        let artifact = upload(
+            &store,
+            "speller.pkg",
+            Some(5),
+            poem::Body::from_vec(b"hello".to_vec()),
+            5,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(artifact.size, 5);
+        assert_eq!(artifact.sha256, HELLO_SHA256);
+        assert_eq!(artifact.url, "https://pahkat.example/speller.pkg");
+        assert_eq!(std::fs::read(store.path("speller.pkg")).unwrap(), b"hello");
+    }
+}
@@ -0,0 +1,63 @@
+use std::ops::{Deref, DerefMut};
+
+use poem::{IntoResponse, Response};
+
+use poem_openapi::{
+    payload::Payload,
+    registry::{MetaMediaType, MetaResponse, MetaResponses, MetaSchemaRef, Registry},
+    types::Type,
+    ApiResponse,
+};
+
+/// An Atom feed payload.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Atom<T>(pub T);
+
+impl<T> Deref for Atom<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Atom<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<T: Send> Payload for Atom<T> {
+    const CONTENT_TYPE: &'static str = "application/atom+xml";
+
+    fn schema_ref() -> MetaSchemaRef {
+        String::schema_ref()
+    }
+}
+
+impl<T: Into<String> + Send> IntoResponse for Atom<T> {
+    fn into_response(self) -> Response {
+        Response::builder()
+            .content_type(Self::CONTENT_TYPE)
+            .header("Content-Disposition", "inline")
+            .body(self.0.into())
+    }
+}
+
+impl<T: Into<String> + Send> ApiResponse for Atom<T> {
+    fn meta() -> MetaResponses {
+        MetaResponses {
+            responses: vec![MetaResponse {
+                description: "",
+                status: Some(200),
+                content: vec![MetaMediaType {
+                    content_type: Self::CONTENT_TYPE,
+                    schema: Self::schema_ref(),
+                }],
+                headers: vec![],
+            }],
+        }
+    }
+
+    fn register(_registry: &mut Registry) {}
+}
@@ -0,0 +1,265 @@
+use chrono::{DateTime, Utc};
+use poem::{
+    http::{header, StatusCode},
+    Request,
+};
+use poem_openapi::payload::Response;
+use sha2::{Digest, Sha256};
+
+use crate::Config;
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// The validators a client can use to revalidate a cached response.
+#[derive(Debug, Clone)]
+pub(crate) struct Validators {
+    etag: String,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    /// A strong ETag derived from a hash of `content`.
+    pub fn new(content: &[u8], last_modified: Option<DateTime<Utc>>) -> Self {
+        let hash = Sha256::digest(content);
+        Self {
+            etag: format!("\"{}\"", hex::encode(&hash[..16])),
+            last_modified,
+        }
+    }
+
+    /// Uses an ETag that was computed ahead of time.
+    pub fn with_etag(etag: String, last_modified: Option<DateTime<Utc>>) -> Self {
+        Self {
+            etag,
+            last_modified,
+        }
+    }
+
+    /// Whether the client's cached copy is still current.
+    ///
+    /// `If-Modified-Since` is only considered without `If-None-Match`.
+    pub fn is_not_modified(&self, req: &Request) -> bool {
+        if let Some(value) = req.headers().get(header::IF_NONE_MATCH) {
+            let value = match value.to_str() {
+                Ok(v) => v,
+                Err(_) => return false,
+            };
+            return value
+                .split(',')
+                .map(|x| x.trim())
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.etag);
+        }
+
+        let since = req
+            .headers()
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| DateTime::parse_from_rfc2822(x).ok());
+        match (since, self.last_modified) {
+            // HTTP dates have second precision
+            (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    /// Whether a `Range` request may be served partially, i.e. the client's
+    /// `If-Range` validator (if any) still matches.
+    pub fn is_range_current(&self, req: &Request) -> bool {
+        let value = match req.headers().get(header::IF_RANGE) {
+            Some(v) => match v.to_str() {
+                Ok(v) => v.trim(),
+                Err(_) => return false,
+            },
+            None => return true,
+        };
+
+        // Only strong validators may be used with `If-Range`.
+        if value.starts_with('"') {
+            return value == self.etag;
+        }
+
+        match (DateTime::parse_from_rfc2822(value), self.last_modified) {
+            (Ok(date), Some(modified)) => modified.timestamp() == date.timestamp(),
+            _ => false,
+        }
+    }
+
+    fn apply<T>(&self, config: &Config, response: Response<T>) -> Response<T> {
+        let response = response
+            .header(header::ETAG, self.etag.as_str())
+            .header(header::CACHE_CONTROL, config.cache_control.as_str());
+        match self.last_modified {
+            Some(modified) => response.header(
+                header::LAST_MODIFIED,
+                modified.format(HTTP_DATE_FORMAT).to_string(),
+            ),
+            None => response,
+        }
+    }
+}
+
+/// Responds with `body` and its cache validators.
+pub(crate) fn cached<T>(config: &Config, validators: &Validators, body: T) -> Response<T> {
+    validators.apply(config, Response::new(body))
+}
+
+/// Responds with `304 Not Modified`; `empty` is the (unsent) body.
+pub(crate) fn not_modified<T>(config: &Config, validators: &Validators, empty: T) -> Response<T> {
+    validators.apply(
+        config,
+        Response::new(empty).status(StatusCode::NOT_MODIFIED),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = "\"3e8-65a14a39\"";
+
+    fn modified() -> DateTime<Utc> {
+        "2024-01-12T14:22:17Z".parse().unwrap()
+    }
+
+    fn validators() -> Validators {
+        Validators::with_etag(ETAG.to_string(), Some(modified()))
+    }
+
+    fn with_if_range(value: &str) -> Request {
+        Request::builder().header(header::IF_RANGE, value).finish()
+    }
+
+    fn with_if_none_match(value: &str) -> Request {
+        Request::builder()
+            .header(header::IF_NONE_MATCH, value)
+            .finish()
+    }
+
+    fn with_if_modified_since(since: DateTime<Utc>) -> Request {
+        Request::builder()
+            .header(
+                header::IF_MODIFIED_SINCE,
+                since.format(HTTP_DATE_FORMAT).to_string(),
+            )
+            .finish()
+    }
+
+    #[test]
+    fn unconditional_requests_are_modified() {
+        assert!(!validators().is_not_modified(&Request::builder().finish()));
+    }
+
+    #[test]
+    fn matching_if_none_match_is_not_modified() {
+        assert!(validators().is_not_modified(&with_if_none_match(ETAG)));
+        assert!(!validators().is_not_modified(&with_if_none_match("\"3e8-00000000\"")));
+    }
+
+    #[test]
+    fn weak_if_none_match_compares_weakly() {
+        let weak = format!("W/{}", ETAG);
+        assert!(validators().is_not_modified(&with_if_none_match(&weak)));
+        assert!(!validators().is_not_modified(&with_if_none_match("W/\"3e8-00000000\"")));
+    }
+
+    #[test]
+    fn if_none_match_wildcard_is_not_modified() {
+        assert!(validators().is_not_modified(&with_if_none_match("*")));
+    }
+
+    #[test]
+    fn if_none_match_lists_match_any_entry() {
+        let list = format!("\"3e8-00000000\", W/\"3e8-11111111\",{}", ETAG);
+        assert!(validators().is_not_modified(&with_if_none_match(&list)));
+        assert!(!validators()
+            .is_not_modified(&with_if_none_match("\"3e8-00000000\", W/\"3e8-11111111\"")));
+    }
+
+    #[test]
+    fn if_modified_since_has_second_precision() {
+        // Sub-second precision is lost when formatting an HTTP date
+        let modified = modified() + chrono::Duration::milliseconds(500);
+        let validators = Validators::with_etag(ETAG.to_string(), Some(modified));
+
+        assert!(validators.is_not_modified(&with_if_modified_since(modified)));
+        assert!(validators.is_not_modified(&with_if_modified_since(
+            modified + chrono::Duration::hours(1)
+        )));
+        assert!(!validators.is_not_modified(&with_if_modified_since(
+            modified - chrono::Duration::seconds(1)
+        )));
+
+        let unknown = Validators::with_etag(ETAG.to_string(), None);
+        assert!(!unknown.is_not_modified(&with_if_modified_since(modified)));
+    }
+
+    #[test]
+    fn unparsable_if_modified_since_is_modified() {
+        let req = Request::builder()
+            .header(header::IF_MODIFIED_SINCE, "yesterday")
+            .finish();
+        assert!(!validators().is_not_modified(&req));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        let current = modified().format(HTTP_DATE_FORMAT).to_string();
+
+        let stale_etag = Request::builder()
+            .header(header::IF_NONE_MATCH, "\"3e8-00000000\"")
+            .header(header::IF_MODIFIED_SINCE, &current)
+            .finish();
+        assert!(!validators().is_not_modified(&stale_etag));
+
+        let stale_date = Request::builder()
+            .header(header::IF_NONE_MATCH, ETAG)
+            .header(
+                header::IF_MODIFIED_SINCE,
+                (modified() - chrono::Duration::days(1))
+                    .format(HTTP_DATE_FORMAT)
+                    .to_string(),
+            )
+            .finish();
+        assert!(validators().is_not_modified(&stale_date));
+    }
+
+    #[test]
+    fn range_without_if_range_is_current() {
+        assert!(validators().is_range_current(&Request::builder().finish()));
+    }
+
+    #[test]
+    fn matching_if_range_etag_is_current() {
+        assert!(validators().is_range_current(&with_if_range(ETAG)));
+    }
+
+    #[test]
+    fn stale_if_range_etag_is_not_current() {
+        assert!(!validators().is_range_current(&with_if_range("\"3e8-00000000\"")));
+    }
+
+    #[test]
+    fn weak_if_range_etag_is_not_current() {
+        let weak = format!("W/{}", ETAG);
+        assert!(!validators().is_range_current(&with_if_range(&weak)));
+    }
+
+    #[test]
+    fn if_range_date_must_match_last_modified() {
+        let current = modified().format(HTTP_DATE_FORMAT).to_string();
+        assert!(validators().is_range_current(&with_if_range(&current)));
+
+        let stale = (modified() - chrono::Duration::hours(1))
+            .format(HTTP_DATE_FORMAT)
+            .to_string();
+        assert!(!validators().is_range_current(&with_if_range(&stale)));
+
+        let unknown = Validators::with_etag(ETAG.to_string(), None);
+        assert!(!unknown.is_range_current(&with_if_range(&current)));
+    }
+
+    #[test]
+    fn unparsable_if_range_is_not_current() {
+        assert!(!validators().is_range_current(&with_if_range("yesterday")));
+    }
+}
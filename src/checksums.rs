@@ -0,0 +1,371 @@
+use std::{path::Path, time::Duration};
+
+use async_graphql::SimpleObject;
+use once_cell::sync::Lazy;
+use pahkat_types::payload::{Payload, Target};
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{artifacts, Config};
+
+/// Fetches artifacts for verification. The overall timeout bounds how long a
+/// publish request can be held up by a slow artifact host.
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(10 * 60))
+        .user_agent(concat!("pahkat-reposrv/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("Could not build verification HTTP client")
+});
+
+/// Name of the sidecar file next to a package's `index.toml`. Checksums are
+/// kept out of the descriptor, whose format `pahkat-types` defines for clients.
+pub(crate) const CHECKSUMS_FILE: &str = "checksums.toml";
+
+/// The expected hashes of one release target's payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Object, SimpleObject)]
+pub(crate) struct PayloadChecksum {
+    pub version: String,
+    pub channel: Option<String>,
+    pub platform: String,
+    pub arch: Option<String>,
+    /// Hex encoded SHA-256 of the payload
+    pub sha256: String,
+    /// Hex encoded BLAKE3 of the payload
+    pub blake3: Option<String>,
+}
+
+impl PayloadChecksum {
+    fn is_for(&self, other: &PayloadChecksum) -> bool {
+        self.version == other.version
+            && self.channel == other.channel
+            && self.platform == other.platform
+            && self.arch == other.arch
+    }
+
+    fn is_for_target(&self, version: &str, channel: Option<&str>, target: &Target) -> bool {
+        self.version == version
+            && self.channel.as_deref() == channel
+            && self.platform == target.platform
+            && self.arch == target.arch
+    }
+}
+
+/// The contents of `checksums.toml`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct ChecksumFile {
+    #[serde(default)]
+    pub checksum: Vec<PayloadChecksum>,
+}
+
+impl ChecksumFile {
+    pub fn read(package_path: &Path) -> Result<Self, std::io::Error> {
+        let path = package_path.join(CHECKSUMS_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let file = std::fs::read_to_string(path)?;
+        ::toml::from_str(&file).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the file, or removes it once no checksums are left.
+    pub fn write(&self, package_path: &Path) -> Result<(), std::io::Error> {
+        let path = package_path.join(CHECKSUMS_FILE);
+        if self.checksum.is_empty() {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+
+        let file = ::toml::to_string_pretty(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        std::fs::write(path, file)
+    }
+
+    /// Adds `checksum`, replacing any earlier one for the same target.
+    pub fn upsert(&mut self, checksum: PayloadChecksum) {
+        self.checksum.retain(|x| !x.is_for(&checksum));
+        self.checksum.push(checksum);
+    }
+
+    /// Drops the checksum for a target of the release `version` in `channel`,
+    /// returning whether there was one.
+    pub fn remove(&mut self, version: &str, channel: Option<&str>, target: &Target) -> bool {
+        let len = self.checksum.len();
+        self.checksum
+            .retain(|x| !x.is_for_target(version, channel, target));
+        self.checksum.len() != len
+    }
+}
+
+/// The checksum recorded for a target of the release `version` in `channel`.
+pub(crate) fn find(
+    checksums: &[PayloadChecksum],
+    version: &str,
+    channel: Option<&str>,
+    target: &Target,
+) -> Option<PayloadChecksum> {
+    checksums
+        .iter()
+        .find(|x| x.is_for_target(version, channel, target))
+        .cloned()
+}
+
+/// Whether `value` looks like a hex encoded 32-byte digest.
+pub(crate) fn is_valid_digest(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum VerifyError {
+    #[error("Could not read artifact: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not fetch artifact: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("Artifact URL responded with status {0}")]
+    Status(reqwest::StatusCode),
+
+    #[error("Artifact is {actual} bytes, but the payload declares {expected}")]
+    Size { expected: u64, actual: u64 },
+
+    #[error("Artifact is larger than {limit} bytes")]
+    TooLarge { limit: u64 },
+
+    #[error("Artifact {algorithm} is {actual}, expected {expected}")]
+    Hash {
+        algorithm: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+fn payload_size(payload: &Payload) -> Option<u64> {
+    match payload {
+        Payload::WindowsExecutable(p) => Some(p.size),
+        Payload::MacOSPackage(p) => Some(p.size),
+        Payload::TarballPackage(p) => Some(p.size),
+        _ => None,
+    }
+}
+
+/// Size and hex encoded hashes of an artifact.
+#[derive(Debug, Clone)]
+pub(crate) struct Digests {
+    pub size: u64,
+    pub sha256: String,
+    pub blake3: String,
+}
+
+/// Computes the size and hashes of an artifact as it is read in chunks.
+pub(crate) struct Hashes {
+    size: u64,
+    sha256: Sha256,
+    blake3: blake3::Hasher,
+}
+
+impl Hashes {
+    pub fn new() -> Self {
+        Self {
+            size: 0,
+            sha256: Sha256::new(),
+            blake3: blake3::Hasher::new(),
+        }
+    }
+
+    /// Number of bytes hashed so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.size += chunk.len() as u64;
+        self.sha256.update(chunk);
+        self.blake3.update(chunk);
+    }
+
+    pub fn finish(self) -> Digests {
+        Digests {
+            size: self.size,
+            sha256: hex::encode(self.sha256.finalize()),
+            blake3: self.blake3.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Hashes the artifact at `url`, from `artifact_root` if the URL points at an
+/// artifact hosted by this server, otherwise by downloading it.
+///
+/// Downloads are abandoned once more than `limit` bytes arrive.
+async fn hash_artifact(config: &Config, url: &str, limit: u64) -> Result<Digests, VerifyError> {
+    let mut hashes = Hashes::new();
+
+    let local = artifacts::hosted_file_name(config, url)
+        .and_then(|name| artifacts::local_path(config, name));
+
+    if let Some(path) = local {
+        tracing::debug!("Verifying payload against {:?}", &path);
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hashes.update(&buf[..n]);
+        }
+        return Ok(hashes.finish());
+    }
+
+    tracing::debug!("Verifying payload against {}", url);
+    let mut response = CLIENT.get(url).send().await?;
+    if !response.status().is_success() {
+        return Err(VerifyError::Status(response.status()));
+    }
+    if response
+        .content_length()
+        .map(|x| x > limit)
+        .unwrap_or(false)
+    {
+        return Err(VerifyError::TooLarge { limit });
+    }
+    while let Some(chunk) = response.chunk().await? {
+        hashes.update(&chunk);
+        if hashes.size() > limit {
+            return Err(VerifyError::TooLarge { limit });
+        }
+    }
+    Ok(hashes.finish())
+}
+
+/// Checks that the payload of `target` has the declared size and, when a
+/// checksum is given, the declared hashes.
+pub(crate) async fn verify(
+    config: &Config,
+    target: &Target,
+    checksum: Option<&PayloadChecksum>,
+) -> Result<(), VerifyError> {
+    let declared_size = payload_size(&target.payload);
+    let limit = declared_size
+        .unwrap_or(config.max_artifact_size)
+        .min(config.max_artifact_size);
+    let digests = hash_artifact(config, target.payload.url().as_str(), limit).await?;
+
+    if let Some(expected) = declared_size {
+        if expected != digests.size {
+            return Err(VerifyError::Size {
+                expected,
+                actual: digests.size,
+            });
+        }
+    }
+
+    let checksum = match checksum {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    if !digests.sha256.eq_ignore_ascii_case(&checksum.sha256) {
+        return Err(VerifyError::Hash {
+            algorithm: "sha256",
+            expected: checksum.sha256.clone(),
+            actual: digests.sha256,
+        });
+    }
+
+    if let Some(expected) = checksum.blake3.as_deref() {
+        if !digests.blake3.eq_ignore_ascii_case(expected) {
+            return Err(VerifyError::Hash {
+                algorithm: "blake3",
+                expected: expected.to_string(),
+                actual: digests.blake3,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: &str = r#"
+platform = "windows"
+arch = "x86_64"
+
+[dependencies]
+
+[payload]
+type = "TarballPackage"
+url = "https://pahkat.example/artifacts/speller-sme.txz"
+size = 1
+installed_size = 1
+"#;
+
+    fn checksum(version: &str, sha256: char) -> PayloadChecksum {
+        PayloadChecksum {
+            version: version.to_string(),
+            channel: None,
+            platform: "windows".to_string(),
+            arch: Some("x86_64".to_string()),
+            sha256: sha256.to_string().repeat(64),
+            blake3: None,
+        }
+    }
+
+    #[test]
+    fn upsert_replaces_the_checksum_of_the_same_target() {
+        let mut file = ChecksumFile::default();
+        file.upsert(checksum("1.0.0", 'a'));
+        file.upsert(checksum("1.1.0", 'b'));
+        file.upsert(checksum("1.0.0", 'c'));
+
+        assert_eq!(
+            file.checksum,
+            [checksum("1.1.0", 'b'), checksum("1.0.0", 'c')]
+        );
+    }
+
+    #[test]
+    fn remove_only_drops_the_matching_target() {
+        let target = ::toml::from_str::<Target>(TARGET).unwrap();
+        let mut file = ChecksumFile::default();
+        file.upsert(checksum("1.0.0", 'a'));
+        file.upsert(checksum("1.1.0", 'b'));
+
+        assert!(!file.remove("1.0.0", Some("nightly"), &target));
+        assert!(file.remove("1.0.0", None, &target));
+        assert!(!file.remove("1.0.0", None, &target));
+        assert_eq!(file.checksum, [checksum("1.1.0", 'b')]);
+        assert_eq!(
+            find(&file.checksum, "1.1.0", None, &target),
+            Some(checksum("1.1.0", 'b'))
+        );
+    }
+
+    #[test]
+    fn empty_files_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = ::toml::from_str::<Target>(TARGET).unwrap();
+
+        let mut file = ChecksumFile::default();
+        file.upsert(checksum("1.0.0", 'a'));
+        file.write(dir.path()).unwrap();
+        assert_eq!(
+            ChecksumFile::read(dir.path()).unwrap().checksum,
+            [checksum("1.0.0", 'a')]
+        );
+
+        file.remove("1.0.0", None, &target);
+        file.write(dir.path()).unwrap();
+        assert!(!dir.path().join(CHECKSUMS_FILE).exists());
+        assert!(ChecksumFile::read(dir.path()).unwrap().checksum.is_empty());
+    }
+}
@@ -0,0 +1,671 @@
+use anyhow::{anyhow, bail, Context};
+use pahkat_types::{
+    fbs::pahkat as fb,
+    package::{Descriptor, DescriptorData, Package, Release, Version},
+    payload::{macos, tarball, windows, Payload, Target},
+};
+
+fn decode_strings<'a>(keys: Option<fbs::Vector<'a, fbs::ForwardsUOffset<&'a str>>>) -> Vec<String> {
+    match keys {
+        Some(keys) => keys.iter().map(str::to_string).collect(),
+        None => vec![],
+    }
+}
+
+fn decode_map<'a>(
+    keys: Option<fbs::Vector<'a, fbs::ForwardsUOffset<&'a str>>>,
+    values: Option<fbs::Vector<'a, fbs::ForwardsUOffset<&'a str>>>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let keys = decode_strings(keys);
+    let values = decode_strings(values);
+    if keys.len() != values.len() {
+        bail!("{} keys but {} values", keys.len(), values.len());
+    }
+    Ok(keys.into_iter().zip(values).collect())
+}
+
+fn decode_windows_exe(
+    payload: fb::WindowsExecutable<&[u8]>,
+) -> anyhow::Result<windows::Executable> {
+    use pahkat_types::fbs::pahkat::{WindowsExecutableFlag, WindowsExecutableKind};
+    use pahkat_types::payload::windows::RebootSpec;
+
+    let kind = match payload.kind()? {
+        WindowsExecutableKind::Msi => Some("msi".to_string()),
+        WindowsExecutableKind::Nsis => Some("nsis".to_string()),
+        WindowsExecutableKind::Inno => Some("inno".to_string()),
+        _ => None,
+    };
+
+    let flags = payload.flags()?;
+    let mut requires_reboot = vec![];
+    if flags & WindowsExecutableFlag::RequiresRebootOnInstall as u8 != 0 {
+        requires_reboot.push(RebootSpec::Install);
+    }
+    if flags & WindowsExecutableFlag::RequiresRebootOnUpdate as u8 != 0 {
+        requires_reboot.push(RebootSpec::Update);
+    }
+    if flags & WindowsExecutableFlag::RequiresRebootOnUninstall as u8 != 0 {
+        requires_reboot.push(RebootSpec::Uninstall);
+    }
+
+    Ok(windows::Executable {
+        url: payload.url()?.parse()?,
+        product_code: payload.product_code()?.to_string(),
+        kind,
+        args: payload.args()?.map(str::to_string),
+        uninstall_args: payload.uninstall_args()?.map(str::to_string),
+        requires_reboot,
+        size: payload.size()?,
+        installed_size: payload.installed_size()?,
+    })
+}
+
+fn decode_macos_pkg(payload: fb::MacOSPackage<&[u8]>) -> anyhow::Result<macos::Package> {
+    use pahkat_types::fbs::pahkat::MacOSPackageFlag;
+    use pahkat_types::payload::macos::{InstallTarget, RebootSpec};
+
+    let flags = payload.flags()?;
+    let mut requires_reboot = vec![];
+    if flags & MacOSPackageFlag::RequiresRebootOnInstall as u8 != 0 {
+        requires_reboot.push(RebootSpec::Install);
+    }
+    if flags & MacOSPackageFlag::RequiresRebootOnUpdate as u8 != 0 {
+        requires_reboot.push(RebootSpec::Update);
+    }
+    if flags & MacOSPackageFlag::RequiresRebootOnUninstall as u8 != 0 {
+        requires_reboot.push(RebootSpec::Uninstall);
+    }
+
+    let mut targets = vec![];
+    if flags & MacOSPackageFlag::TargetSystem as u8 != 0 {
+        targets.push(InstallTarget::System);
+    }
+    if flags & MacOSPackageFlag::TargetUser as u8 != 0 {
+        targets.push(InstallTarget::User);
+    }
+
+    Ok(macos::Package {
+        url: payload.url()?.parse()?,
+        pkg_id: payload.pkg_id()?.to_string(),
+        requires_reboot,
+        targets: targets.into_iter().collect(),
+        size: payload.size()?,
+        installed_size: payload.installed_size()?,
+    })
+}
+
+fn decode_tarball_pkg(payload: fb::TarballPackage<&[u8]>) -> anyhow::Result<tarball::Package> {
+    Ok(tarball::Package {
+        url: payload.url()?.parse()?,
+        size: payload.size()?,
+        installed_size: payload.installed_size()?,
+    })
+}
+
+fn decode_target(target: fb::Target<&[u8]>) -> anyhow::Result<Target> {
+    use pahkat_types::fbs::pahkat::fbs_gen::PayloadType;
+
+    let payload = match target.payload_type()? {
+        PayloadType::WindowsExecutable => Payload::WindowsExecutable(decode_windows_exe(
+            target
+                .payload_as_windows_executable()?
+                .ok_or_else(|| anyhow!("missing windows payload"))?,
+        )?),
+        PayloadType::MacOSPackage => Payload::MacOSPackage(decode_macos_pkg(
+            target
+                .payload_as_macos_package()?
+                .ok_or_else(|| anyhow!("missing macOS payload"))?,
+        )?),
+        PayloadType::TarballPackage => Payload::TarballPackage(decode_tarball_pkg(
+            target
+                .payload_as_tarball_package()?
+                .ok_or_else(|| anyhow!("missing tarball payload"))?,
+        )?),
+        _ => bail!("unknown payload type"),
+    };
+
+    let dependencies = decode_map(target.dependencies_keys()?, target.dependencies_values()?)?
+        .into_iter()
+        .map(|(key, value)| {
+            key.parse()
+                .map(|key| (key, value))
+                .map_err(|_| anyhow!("invalid dependency key `{}`", key))
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(Target {
+        platform: target.platform()?.to_string(),
+        arch: target.arch()?.map(str::to_string),
+        dependencies,
+        payload,
+    })
+}
+
+fn decode_release(release: fb::Release<&[u8]>) -> anyhow::Result<Release> {
+    let version = match release.version_type()? {
+        2 => Version::Semantic(release.version()?.parse()?),
+        other => bail!("unsupported version type {}", other),
+    };
+
+    let target = match release.target()? {
+        Some(targets) => targets
+            .iter()
+            .map(decode_target)
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        None => vec![],
+    };
+
+    Ok(Release {
+        version,
+        channel: release.channel()?.map(str::to_string),
+        authors: decode_strings(release.authors()?),
+        license: release.license()?.map(str::to_string),
+        license_url: release.license_url()?.map(str::parse).transpose()?,
+        target,
+    })
+}
+
+fn decode_descriptor(descriptor: fb::Descriptor<&[u8]>) -> anyhow::Result<Descriptor> {
+    let id = descriptor.id()?.to_string();
+
+    let release = match descriptor.release()? {
+        Some(releases) => releases
+            .iter()
+            .map(decode_release)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .with_context(|| format!("invalid release in `{}`", id))?,
+        None => vec![],
+    };
+
+    Ok(Descriptor {
+        package: DescriptorData {
+            id,
+            tags: decode_strings(descriptor.tags()?),
+        },
+        name: decode_map(descriptor.name_keys()?, descriptor.name_values()?)?
+            .into_iter()
+            .collect(),
+        description: decode_map(
+            descriptor.description_keys()?,
+            descriptor.description_values()?,
+        )?
+        .into_iter()
+        .collect(),
+        release,
+    })
+}
+
+/// Reads a flatbuffer index produced by `indexing::build_index` back into packages.
+pub(crate) fn decode_index(data: &[u8]) -> anyhow::Result<Vec<Package>> {
+    let root = fb::Packages::get_root(data)?;
+
+    let keys = decode_strings(root.packages_keys()?);
+    let descriptors = match root.packages_values()? {
+        Some(values) => values
+            .iter()
+            .map(decode_descriptor)
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        None => vec![],
+    };
+
+    if keys.len() != descriptors.len() {
+        bail!(
+            "index has {} keys but {} descriptors",
+            keys.len(),
+            descriptors.len()
+        );
+    }
+
+    for (key, descriptor) in keys.iter().zip(descriptors.iter()) {
+        if key != &descriptor.package.id {
+            bail!(
+                "key `{}` maps to descriptor `{}`",
+                key,
+                descriptor.package.id
+            );
+        }
+    }
+
+    Ok(descriptors.into_iter().map(Package::Concrete).collect())
+}
+
+/// Applies the same lossy mapping the flatbuffer encoding does, so that a
+/// package can be compared with its decoded counterpart.
+fn normalize_package(package: &Package) -> Package {
+    use pahkat_types::payload::windows::RebootSpec as WindowsReboot;
+    use pahkat_types::payload::{macos::InstallTarget, macos::RebootSpec as MacOSReboot};
+
+    let mut package = package.clone();
+    let descriptor = match &mut package {
+        Package::Concrete(v) => v,
+        _ => return package,
+    };
+
+    for target in descriptor
+        .release
+        .iter_mut()
+        .flat_map(|r| r.target.iter_mut())
+    {
+        match &mut target.payload {
+            Payload::WindowsExecutable(p) => {
+                if !matches!(p.kind.as_deref(), Some("msi" | "nsis" | "inno")) {
+                    p.kind = None;
+                }
+                p.requires_reboot = [
+                    WindowsReboot::Install,
+                    WindowsReboot::Update,
+                    WindowsReboot::Uninstall,
+                ]
+                .into_iter()
+                .filter(|x| p.requires_reboot.contains(x))
+                .collect();
+            }
+            Payload::MacOSPackage(p) => {
+                p.requires_reboot = [
+                    MacOSReboot::Install,
+                    MacOSReboot::Update,
+                    MacOSReboot::Uninstall,
+                ]
+                .into_iter()
+                .filter(|x| p.requires_reboot.contains(x))
+                .collect();
+
+                let targets = if p.targets.is_empty() {
+                    vec![InstallTarget::System]
+                } else {
+                    [InstallTarget::System, InstallTarget::User]
+                        .into_iter()
+                        .filter(|x| p.targets.iter().any(|t| t == x))
+                        .collect()
+                };
+                p.targets = targets.into_iter().collect();
+            }
+            _ => {}
+        }
+    }
+
+    package
+}
+
+/// Checks that a built index decodes back to the packages it was built from.
+pub(crate) fn verify_index(packages: &[Package], data: &[u8]) -> anyhow::Result<()> {
+    let decoded = decode_index(data).context("built index could not be decoded")?;
+
+    if decoded.len() != packages.len() {
+        bail!(
+            "built index has {} packages, expected {}",
+            decoded.len(),
+            packages.len()
+        );
+    }
+
+    for (expected, actual) in packages.iter().zip(decoded.iter()) {
+        // Compare the serialized forms so this doesn't depend on which
+        // pahkat-types structs implement `PartialEq`.
+        let expected_value = serde_json::to_value(normalize_package(expected))?;
+        let actual_value = serde_json::to_value(actual)?;
+        if expected_value != actual_value {
+            bail!("package `{}` does not round-trip", expected.id());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use fbs::FlatBufferBuilder;
+    use pahkat_types::payload::{macos, windows};
+
+    use super::*;
+    use crate::indexing::build_index;
+
+    const SPELLER: &str = r#"
+[package]
+id = "speller-sme"
+tags = ["cat:speller", "lang:sme"]
+
+[name]
+en = "Northern Sami speller"
+se = "Davvisámegiela sátnedárkkisteaddji"
+
+[description]
+en = "Spell checker"
+
+[[release]]
+version = "1.2.0"
+channel = "nightly"
+authors = ["Divvun"]
+license = "GPL-3.0"
+license_url = "https://www.gnu.org/licenses/gpl-3.0.html"
+
+[[release.target]]
+platform = "windows"
+arch = "x86_64"
+
+[release.target.dependencies]
+"https://pahkat.uit.no/tools/packages/windivvun" = "*"
+
+[release.target.payload]
+type = "WindowsExecutable"
+url = "https://pahkat.uit.no/artifacts/speller-sme_1.2.0_windows.exe"
+product_code = "{6A1F2C8E-2D11-4D0E-9E3B-1B2A3C4D5E6F}"
+kind = "inno"
+args = "/VERYSILENT"
+uninstall_args = "/VERYSILENT"
+requires_reboot = ["install", "update"]
+size = 1024
+installed_size = 4096
+
+[[release.target]]
+platform = "macos"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "MacOSPackage"
+url = "https://pahkat.uit.no/artifacts/speller-sme_1.2.0_macos.pkg"
+pkg_id = "no.divvun.speller.sme"
+requires_reboot = []
+targets = ["system", "user"]
+size = 2048
+installed_size = 8192
+
+[[release]]
+version = "1.1.0"
+authors = []
+
+[[release.target]]
+platform = "linux"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "TarballPackage"
+url = "https://pahkat.uit.no/artifacts/speller-sme_1.1.0_linux.txz"
+size = 512
+installed_size = 1024
+"#;
+
+    const UNINSTALL_REBOOT: &str = r#"
+[package]
+id = "keyboard-sme"
+tags = []
+
+[name]
+en = "Northern Sami keyboard"
+
+[description]
+en = "Keyboard layout"
+
+[[release]]
+version = "0.1.0"
+authors = []
+
+[[release.target]]
+platform = "windows"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "WindowsExecutable"
+url = "https://pahkat.uit.no/artifacts/keyboard-sme_0.1.0_windows.exe"
+product_code = "{00000000-0000-0000-0000-000000000001}"
+requires_reboot = ["uninstall"]
+size = 10
+installed_size = 20
+
+[[release.target]]
+platform = "macos"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "MacOSPackage"
+url = "https://pahkat.uit.no/artifacts/keyboard-sme_0.1.0_macos.pkg"
+pkg_id = "no.divvun.keyboard.sme"
+requires_reboot = ["uninstall"]
+targets = ["user"]
+size = 10
+installed_size = 20
+"#;
+
+    fn package(descriptor: &str) -> Package {
+        Package::Concrete(::toml::from_str::<Descriptor>(descriptor).unwrap())
+    }
+
+    fn round_trip(packages: &[Package]) -> Vec<Package> {
+        let mut builder = FlatBufferBuilder::new();
+        let data = build_index(&mut builder, packages).unwrap();
+        verify_index(packages, data).unwrap();
+        decode_index(data).unwrap()
+    }
+
+    fn targets(package: &Package) -> &[Target] {
+        match package {
+            Package::Concrete(v) => &v.release[0].target,
+            _ => panic!("not a concrete package"),
+        }
+    }
+
+    #[test]
+    fn empty_index_round_trips() {
+        assert!(round_trip(&[]).is_empty());
+    }
+
+    #[test]
+    fn all_payload_types_round_trip() {
+        let packages = [package(SPELLER), package(UNINSTALL_REBOOT)];
+        let decoded = round_trip(&packages);
+
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].id(), "speller-sme");
+        assert_eq!(decoded[1].id(), "keyboard-sme");
+    }
+
+    #[test]
+    fn windows_uninstall_reboot_round_trips() {
+        let decoded = round_trip(&[package(UNINSTALL_REBOOT)]);
+
+        match &targets(&decoded[0])[0].payload {
+            Payload::WindowsExecutable(p) => {
+                assert_eq!(p.requires_reboot, vec![windows::RebootSpec::Uninstall]);
+            }
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn macos_uninstall_reboot_round_trips() {
+        let decoded = round_trip(&[package(UNINSTALL_REBOOT)]);
+
+        match &targets(&decoded[0])[1].payload {
+            Payload::MacOSPackage(p) => {
+                assert_eq!(p.requires_reboot, vec![macos::RebootSpec::Uninstall]);
+                assert!(p.targets.iter().all(|x| x == &macos::InstallTarget::User));
+            }
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    mod arbitrary {
+        use std::collections::BTreeMap;
+
+        use proptest::{collection, option, prelude::*, sample};
+        use serde_json::{json, Value};
+
+        use super::*;
+
+        fn text() -> impl Strategy<Value = String> {
+            "\\PC{0,12}"
+        }
+
+        fn package_id() -> impl Strategy<Value = String> {
+            "[a-z][a-z0-9-]{0,11}"
+        }
+
+        fn lang_map() -> impl Strategy<Value = BTreeMap<String, String>> {
+            collection::btree_map("[a-z]{2,3}", text(), 0..3)
+        }
+
+        fn subset(values: &'static [&'static str]) -> impl Strategy<Value = Vec<&'static str>> {
+            sample::subsequence(values, 0..=values.len()).prop_shuffle()
+        }
+
+        fn payload() -> impl Strategy<Value = Value> {
+            let url = package_id().prop_map(|x| format!("https://pahkat.example/artifacts/{}", x));
+            let sizes = (any::<u64>(), any::<u64>());
+
+            prop_oneof![
+                (
+                    url.clone(),
+                    text(),
+                    option::of(sample::select(vec!["msi", "nsis", "inno", "exe"])),
+                    option::of(text()),
+                    option::of(text()),
+                    subset(&["install", "uninstall", "update"]),
+                    sizes.clone(),
+                )
+                    .prop_map(
+                        |(url, product_code, kind, args, uninstall_args, reboot, sizes)| {
+                            json!({
+                                "type": "WindowsExecutable",
+                                "url": url,
+                                "product_code": product_code,
+                                "kind": kind,
+                                "args": args,
+                                "uninstall_args": uninstall_args,
+                                "requires_reboot": reboot,
+                                "size": sizes.0,
+                                "installed_size": sizes.1,
+                            })
+                        }
+                    ),
+                (
+                    url.clone(),
+                    text(),
+                    subset(&["install", "uninstall", "update"]),
+                    subset(&["system", "user"]),
+                    sizes.clone(),
+                )
+                    .prop_map(|(url, pkg_id, reboot, targets, sizes)| {
+                        json!({
+                            "type": "MacOSPackage",
+                            "url": url,
+                            "pkg_id": pkg_id,
+                            "requires_reboot": reboot,
+                            "targets": targets,
+                            "size": sizes.0,
+                            "installed_size": sizes.1,
+                        })
+                    }),
+                (url, sizes).prop_map(|(url, sizes)| {
+                    json!({
+                        "type": "TarballPackage",
+                        "url": url,
+                        "size": sizes.0,
+                        "installed_size": sizes.1,
+                    })
+                }),
+            ]
+        }
+
+        fn target() -> impl Strategy<Value = Value> {
+            (
+                sample::select(vec!["windows", "macos", "linux", "android"]),
+                option::of(sample::select(vec!["x86_64", "i686", "arm64"])),
+                collection::btree_map(
+                    package_id()
+                        .prop_map(|x| format!("https://pahkat.example/tools/packages/{}", x)),
+                    Just("*"),
+                    0..3,
+                ),
+                payload(),
+            )
+                .prop_map(|(platform, arch, dependencies, payload)| {
+                    json!({
+                        "platform": platform,
+                        "arch": arch,
+                        "dependencies": dependencies,
+                        "payload": payload,
+                    })
+                })
+        }
+
+        fn release() -> impl Strategy<Value = Value> {
+            (
+                (0u32..5, 0u32..20, 0u32..50),
+                option::of(sample::select(vec!["nightly", "beta"])),
+                collection::vec(text(), 0..3),
+                option::of(text()),
+                collection::vec(target(), 0..4),
+            )
+                .prop_map(|(version, channel, authors, license, targets)| {
+                    json!({
+                        "version": format!("{}.{}.{}", version.0, version.1, version.2),
+                        "channel": channel,
+                        "authors": authors,
+                        "license": license,
+                        "target": targets,
+                    })
+                })
+        }
+
+        fn descriptor() -> impl Strategy<Value = Descriptor> {
+            (
+                package_id(),
+                collection::vec(text(), 0..3),
+                lang_map(),
+                lang_map(),
+                collection::vec(release(), 0..4),
+            )
+                .prop_map(|(id, tags, name, description, releases)| {
+                    serde_json::from_value(json!({
+                        "package": { "id": id, "tags": tags },
+                        "name": name,
+                        "description": description,
+                        "release": releases,
+                    }))
+                    .unwrap()
+                })
+        }
+
+        /// Packages with distinct ids, as in a repo.
+        fn packages() -> impl Strategy<Value = Vec<Package>> {
+            collection::vec(descriptor(), 0..5).prop_map(|descriptors| {
+                let mut seen = std::collections::HashSet::new();
+                descriptors
+                    .into_iter()
+                    .filter(|x| seen.insert(x.package.id.clone()))
+                    .map(Package::Concrete)
+                    .collect()
+            })
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(128))]
+
+            #[test]
+            fn built_indexes_round_trip(packages in packages()) {
+                let mut builder = FlatBufferBuilder::new();
+                let data = build_index(&mut builder, &packages).unwrap();
+                prop_assert!(verify_index(&packages, data).is_ok());
+
+                let decoded = decode_index(data).unwrap();
+                let ids = decoded.iter().map(|x| x.id()).collect::<Vec<_>>();
+                let expected = packages.iter().map(|x| x.id()).collect::<Vec<_>>();
+                prop_assert_eq!(ids, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn verify_rejects_a_different_index() {
+        let mut builder = FlatBufferBuilder::new();
+        let data = build_index(&mut builder, &[package(SPELLER)]).unwrap();
+
+        assert!(verify_index(&[package(UNINSTALL_REBOOT)], data).is_err());
+        assert!(verify_index(&[], data).is_err());
+    }
+}
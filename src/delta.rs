@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+
+use pahkat_types::package::{Descriptor, Package};
+use poem_openapi::Object;
+
+fn by_id(packages: &[Package]) -> BTreeMap<&str, (&Package, serde_json::Value)> {
+    packages
+        .iter()
+        .map(|p| {
+            let value = serde_json::to_value(p).unwrap_or(serde_json::Value::Null);
+            (p.id(), (p, value))
+        })
+        .collect()
+}
+
+fn concrete(package: &Package) -> Option<Descriptor> {
+    match package {
+        Package::Concrete(v) => Some(v.clone()),
+        _ => None,
+    }
+}
+
+/// The packages that differ between two generations of a repo index.
+#[derive(Debug, Clone, Object)]
+pub(crate) struct IndexDelta {
+    /// The `head_ref` the client asked for a delta from
+    base: String,
+    head: String,
+    /// Set when `base` is no longer known; `added` then holds every package
+    full: bool,
+    added: Vec<Descriptor>,
+    changed: Vec<Descriptor>,
+    removed: Vec<String>,
+}
+
+impl IndexDelta {
+    pub fn between(base: &str, head: &str, old: &[Package], new: &[Package]) -> Self {
+        let old = by_id(old);
+        let new = by_id(new);
+
+        let mut added = vec![];
+        let mut changed = vec![];
+        for (id, (package, value)) in new.iter() {
+            match old.get(id) {
+                None => added.extend(concrete(package)),
+                Some((_, old_value)) if old_value != value => changed.extend(concrete(package)),
+                Some(_) => {}
+            }
+        }
+        let removed = old
+            .keys()
+            .filter(|id| !new.contains_key(*id))
+            .map(|id| id.to_string())
+            .collect();
+
+        Self {
+            base: base.to_string(),
+            head: head.to_string(),
+            full: false,
+            added,
+            changed,
+            removed,
+        }
+    }
+
+    /// A delta that replaces whatever the client has with `packages`.
+    pub fn full(base: &str, head: &str, packages: &[Package]) -> Self {
+        Self {
+            base: base.to_string(),
+            head: head.to_string(),
+            full: true,
+            added: packages.iter().filter_map(concrete).collect(),
+            changed: vec![],
+            removed: vec![],
+        }
+    }
+}
+
+/// Ids of packages that were added, removed or modified between two indexes,
+/// sorted.
+pub(crate) fn changed_package_ids(old: &[Package], new: &[Package]) -> Vec<String> {
+    let old = by_id(old);
+    let new = by_id(new);
+
+    let mut changed = new
+        .iter()
+        .filter(|(id, (_, value))| old.get(*id).map(|(_, x)| x) != Some(value))
+        .map(|(id, _)| id.to_string())
+        .chain(
+            old.keys()
+                .filter(|id| !new.contains_key(*id))
+                .map(|id| id.to_string()),
+        )
+        .collect::<Vec<_>>();
+    changed.sort();
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(id: &str, version: &str) -> Package {
+        let descriptor = format!(
+            r#"
+[package]
+id = "{id}"
+tags = []
+
+[name]
+en = "{id}"
+
+[description]
+en = "{id}"
+
+[[release]]
+version = "{version}"
+authors = []
+
+[[release.target]]
+platform = "windows"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "TarballPackage"
+url = "https://pahkat.example/artifacts/{id}_{version}.txz"
+size = 1
+installed_size = 1
+"#,
+            id = id,
+            version = version
+        );
+        Package::Concrete(::toml::from_str::<Descriptor>(&descriptor).unwrap())
+    }
+
+    /// Three generations of a repo: `a` is updated and then reverted, `c` is
+    /// removed, `d` is added and then updated, and `e` is added last.
+    fn generations() -> [Vec<Package>; 3] {
+        [
+            vec![
+                package("a", "1.0.0"),
+                package("b", "1.0.0"),
+                package("c", "1.0.0"),
+            ],
+            vec![
+                package("b", "1.0.0"),
+                package("a", "1.1.0"),
+                package("d", "1.0.0"),
+            ],
+            vec![
+                package("a", "1.0.0"),
+                package("b", "1.0.0"),
+                package("d", "2.0.0"),
+                package("e", "1.0.0"),
+            ],
+        ]
+    }
+
+    fn ids(descriptors: &[Descriptor]) -> Vec<&str> {
+        descriptors.iter().map(|x| x.package.id.as_str()).collect()
+    }
+
+    fn version(descriptor: &Descriptor) -> String {
+        descriptor.release[0].version.to_string()
+    }
+
+    #[test]
+    fn delta_between_adjacent_generations() {
+        let [gen0, gen1, _] = generations();
+        let delta = IndexDelta::between("gen0", "gen1", &gen0, &gen1);
+
+        assert_eq!((delta.base.as_str(), delta.head.as_str()), ("gen0", "gen1"));
+        assert!(!delta.full);
+        assert_eq!(ids(&delta.added), ["d"]);
+        assert_eq!(ids(&delta.changed), ["a"]);
+        assert_eq!(version(&delta.changed[0]), "1.1.0");
+        assert_eq!(delta.removed, ["c"]);
+    }
+
+    #[test]
+    fn delta_across_generations_only_holds_the_net_change() {
+        let [gen0, gen1, gen2] = generations();
+
+        // `a` was changed and changed back, so a client at gen0 needs nothing
+        // for it; `d` is new to that client, in its latest form.
+        let delta = IndexDelta::between("gen0", "gen2", &gen0, &gen2);
+        assert_eq!(ids(&delta.added), ["d", "e"]);
+        assert_eq!(version(&delta.added[0]), "2.0.0");
+        assert!(delta.changed.is_empty());
+        assert_eq!(delta.removed, ["c"]);
+
+        let delta = IndexDelta::between("gen1", "gen2", &gen1, &gen2);
+        assert_eq!(ids(&delta.added), ["e"]);
+        assert_eq!(ids(&delta.changed), ["a", "d"]);
+        assert!(delta.removed.is_empty());
+    }
+
+    #[test]
+    fn delta_to_the_same_generation_is_empty() {
+        let [_, gen1, _] = generations();
+        let delta = IndexDelta::between("gen1", "gen1", &gen1, &gen1);
+
+        assert!(delta.added.is_empty());
+        assert!(delta.changed.is_empty());
+        assert!(delta.removed.is_empty());
+    }
+
+    #[test]
+    fn full_delta_replaces_everything() {
+        let [_, _, gen2] = generations();
+        let delta = IndexDelta::full("unknown", "gen2", &gen2);
+
+        assert!(delta.full);
+        assert_eq!(ids(&delta.added), ["a", "b", "d", "e"]);
+        assert!(delta.changed.is_empty());
+        assert!(delta.removed.is_empty());
+    }
+
+    #[test]
+    fn changed_ids_cover_additions_removals_and_updates() {
+        let [gen0, gen1, gen2] = generations();
+
+        assert_eq!(changed_package_ids(&gen0, &gen1), ["a", "c", "d"]);
+        assert_eq!(changed_package_ids(&gen1, &gen2), ["a", "d", "e"]);
+        assert_eq!(changed_package_ids(&gen0, &gen2), ["c", "d", "e"]);
+        assert!(changed_package_ids(&gen2, &gen2).is_empty());
+    }
+}
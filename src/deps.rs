@@ -0,0 +1,717 @@
+use std::{collections::HashMap, path};
+
+use async_graphql::SimpleObject;
+use pahkat_types::{package::Package, payload::Target};
+use poem_openapi::Object;
+
+use crate::{
+    release::{self, ReleaseQuery},
+    state::REPO_INDEXES,
+    Config,
+};
+
+/// A dependency key split into the repository it points at and the package id.
+///
+/// Keys are either bare package ids (resolved against the depending package's
+/// own repository) or full package URLs such as
+/// `https://pahkat.uit.no/main/packages/speller-sme?platform=windows`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DependencyRef<'a> {
+    pub repo_url: Option<&'a str>,
+    pub package_id: &'a str,
+}
+
+pub(crate) fn parse_dependency_key(key: &str) -> DependencyRef<'_> {
+    let key = key.split(|c| c == '?' || c == '#').next().unwrap_or(key);
+    let key = key.trim_end_matches('/');
+
+    match key.rsplit_once("/packages/") {
+        Some((repo_url, package_id)) => DependencyRef {
+            repo_url: Some(repo_url.trim_end_matches('/')),
+            package_id,
+        },
+        None => DependencyRef {
+            repo_url: None,
+            package_id: key,
+        },
+    }
+}
+
+/// The packages of one hosted repository, as seen by dependency lookups.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RepoPackages<'a> {
+    pub repo_id: &'a str,
+    pub url: &'a str,
+    /// Other URLs dependency keys may use for this repo (`repo_aliases`)
+    pub aliases: &'a [String],
+    pub packages: &'a [Package],
+    /// Position of each package in `packages`, by id
+    pub package_ids: &'a HashMap<String, usize>,
+}
+
+impl<'a> RepoPackages<'a> {
+    fn is_referenced_by(&self, repo_url: &str) -> bool {
+        std::iter::once(self.url)
+            .chain(self.aliases.iter().map(|x| x.as_str()))
+            .any(|url| url.trim_end_matches('/') == repo_url)
+    }
+
+    pub fn package(&self, package_id: &str) -> Option<&'a Package> {
+        self.package_ids.get(package_id).map(|&i| &self.packages[i])
+    }
+}
+
+/// Finds the repo and package a dependency key refers to, if it is hosted here.
+pub(crate) fn locate<'a>(
+    repos: &[RepoPackages<'a>],
+    from_repo_id: &str,
+    key: &str,
+) -> Option<(RepoPackages<'a>, &'a Package)> {
+    let dep = parse_dependency_key(key);
+
+    repos
+        .iter()
+        .filter(|repo| match dep.repo_url {
+            Some(repo_url) => repo.is_referenced_by(repo_url),
+            None => repo.repo_id == from_repo_id,
+        })
+        .find_map(|repo| repo.package(dep.package_id).map(|p| (*repo, p)))
+}
+
+/// The configured alias URLs of a repo.
+pub(crate) fn repo_aliases<'a>(config: &'a Config, repo_id: &str) -> &'a [String] {
+    config
+        .repo_aliases
+        .get(repo_id)
+        .map(|x| x.as_slice())
+        .unwrap_or_default()
+}
+
+/// Loads the current index of every hosted repo for dependency lookups.
+pub(crate) fn with_hosted_repos<R>(config: &Config, f: impl FnOnce(&[RepoPackages<'_>]) -> R) -> R {
+    let loaded = REPO_INDEXES
+        .get()
+        .unwrap()
+        .iter()
+        .map(|(repo_id, state)| (repo_id, state.load_full()))
+        .collect::<Vec<_>>();
+
+    let repos = loaded
+        .iter()
+        .map(|(repo_id, data)| RepoPackages {
+            repo_id,
+            url: data.repo_index.repository.url.as_str(),
+            aliases: repo_aliases(config, repo_id),
+            packages: &data.packages,
+            package_ids: &data.package_ids,
+        })
+        .collect::<Vec<_>>();
+
+    f(&repos)
+}
+
+/// Returns the dependency keys of `target` that no hosted repo provides.
+///
+/// Packages created since the last index refresh only exist in the git working
+/// tree, so those are checked for on disk as well.
+pub(crate) fn unknown_dependencies(
+    repos: &[RepoPackages<'_>],
+    git_path: &path::Path,
+    repo_id: &str,
+    target: &Target,
+) -> Vec<String> {
+    target
+        .dependencies
+        .keys()
+        .map(|key| key.as_str())
+        .filter(|key| locate(repos, repo_id, key).is_none())
+        .filter(|key| {
+            let dep = parse_dependency_key(key);
+            let dep_repo_id = match dep.repo_url {
+                Some(repo_url) => repos
+                    .iter()
+                    .find(|repo| repo.is_referenced_by(repo_url))
+                    .map(|repo| repo.repo_id),
+                None => Some(repo_id),
+            };
+
+            match dep_repo_id {
+                Some(dep_repo_id) => !git_path
+                    .join(dep_repo_id)
+                    .join("packages")
+                    .join(dep.package_id)
+                    .join("index.toml")
+                    .exists(),
+                None => true,
+            }
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+#[derive(Object, Debug, Clone)]
+pub(crate) struct ReverseDependency {
+    repo_id: String,
+    package_id: String,
+    version: String,
+    channel: Option<String>,
+    platform: String,
+    arch: Option<String>,
+}
+
+/// Lists every release target that depends on the given package.
+pub(crate) fn reverse_dependencies(
+    repos: &[RepoPackages<'_>],
+    repo_id: &str,
+    package_id: &str,
+) -> Vec<ReverseDependency> {
+    let mut out = vec![];
+
+    for repo in repos.iter() {
+        for package in repo.packages.iter() {
+            let descriptor = match package {
+                Package::Concrete(v) => v,
+                _ => continue,
+            };
+
+            for release in descriptor.release.iter() {
+                for target in release.target.iter() {
+                    let depends = target.dependencies.keys().any(|key| {
+                        matches!(
+                            locate(repos, repo.repo_id, key.as_str()),
+                            Some((dep_repo, dep)) if dep_repo.repo_id == repo_id && dep.id() == package_id
+                        )
+                    });
+
+                    if depends {
+                        out.push(ReverseDependency {
+                            repo_id: repo.repo_id.to_string(),
+                            package_id: package.id().to_string(),
+                            version: release.version.to_string(),
+                            channel: release.channel.clone(),
+                            platform: target.platform.clone(),
+                            arch: target.arch.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    out
+}
+
+#[derive(Debug, Clone, Object, SimpleObject)]
+pub(crate) struct ResolvedPackage {
+    repo_id: String,
+    package_id: String,
+    version: String,
+    channel: Option<String>,
+    platform: String,
+    arch: Option<String>,
+    payload_url: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ResolveError {
+    #[error("Package `{key}` required by `{required_by}` is not hosted by any repo")]
+    MissingPackage { key: String, required_by: String },
+
+    #[error("No release of `{0}` matches the requested platform, arch and channel")]
+    NoMatchingRelease(String),
+
+    #[error("Dependency cycle: {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
+}
+
+struct Resolver<'r, 'a> {
+    repos: &'r [RepoPackages<'a>],
+    query: ReleaseQuery<'r>,
+    stack: Vec<String>,
+    resolved: Vec<ResolvedPackage>,
+}
+
+impl<'r, 'a> Resolver<'r, 'a> {
+    fn visit(&mut self, repo: RepoPackages<'a>, package: &'a Package) -> Result<(), ResolveError> {
+        let key = format!("{}/{}", repo.repo_id, package.id());
+
+        if self
+            .resolved
+            .iter()
+            .any(|p| p.repo_id == repo.repo_id && p.package_id == package.id())
+        {
+            return Ok(());
+        }
+
+        if let Some(pos) = self.stack.iter().position(|k| k == &key) {
+            let mut cycle = self.stack[pos..].to_vec();
+            cycle.push(key);
+            return Err(ResolveError::Cycle(cycle));
+        }
+
+        let (release, target) = match package {
+            Package::Concrete(descriptor) => release::select_release(descriptor, &self.query),
+            _ => None,
+        }
+        .ok_or_else(|| ResolveError::NoMatchingRelease(key.clone()))?;
+
+        self.stack.push(key.clone());
+        for dep_key in target.dependencies.keys() {
+            let (dep_repo, dep) =
+                locate(self.repos, repo.repo_id, dep_key.as_str()).ok_or_else(|| {
+                    ResolveError::MissingPackage {
+                        key: dep_key.as_str().to_string(),
+                        required_by: key.clone(),
+                    }
+                })?;
+            self.visit(dep_repo, dep)?;
+        }
+        self.stack.pop();
+
+        self.resolved.push(ResolvedPackage {
+            repo_id: repo.repo_id.to_string(),
+            package_id: package.id().to_string(),
+            version: release.version.to_string(),
+            channel: release.channel.clone(),
+            platform: target.platform.clone(),
+            arch: target.arch.clone(),
+            payload_url: target.payload.url().to_string(),
+        });
+
+        Ok(())
+    }
+}
+
+/// Resolves the full install set of a package, dependencies first.
+///
+/// The requested package is always the last entry.
+pub(crate) fn resolve(
+    repos: &[RepoPackages<'_>],
+    repo_id: &str,
+    package_id: &str,
+    query: ReleaseQuery<'_>,
+) -> Result<Option<Vec<ResolvedPackage>>, ResolveError> {
+    let (repo, package) = match repos
+        .iter()
+        .find(|repo| repo.repo_id == repo_id)
+        .and_then(|repo| repo.package(package_id).map(|p| (*repo, p)))
+    {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let mut resolver = Resolver {
+        repos,
+        query,
+        stack: vec![],
+        resolved: vec![],
+    };
+    resolver.visit(repo, package)?;
+
+    Ok(Some(resolver.resolved))
+}
+
+#[cfg(test)]
+mod tests {
+    use pahkat_types::package::Descriptor;
+
+    use super::*;
+
+    fn package(id: &str, dependencies: &[&str]) -> Package {
+        let dependencies = dependencies
+            .iter()
+            .map(|x| format!("\"{}\" = \"*\"\n", x))
+            .collect::<String>();
+        let descriptor = format!(
+            r#"
+[package]
+id = "{id}"
+tags = []
+
+[name]
+en = "{id}"
+
+[description]
+en = "{id}"
+
+[[release]]
+version = "1.0.0"
+authors = []
+
+[[release.target]]
+platform = "windows"
+
+[release.target.dependencies]
+{dependencies}
+[release.target.payload]
+type = "TarballPackage"
+url = "https://pahkat.example/artifacts/{id}.txz"
+size = 1
+installed_size = 1
+"#,
+            id = id,
+            dependencies = dependencies
+        );
+        Package::Concrete(::toml::from_str::<Descriptor>(&descriptor).unwrap())
+    }
+
+    struct TestRepo {
+        repo_id: &'static str,
+        url: &'static str,
+        aliases: Vec<String>,
+        packages: Vec<Package>,
+        package_ids: HashMap<String, usize>,
+    }
+
+    impl TestRepo {
+        fn new(repo_id: &'static str, url: &'static str, packages: Vec<Package>) -> Self {
+            let package_ids = packages
+                .iter()
+                .enumerate()
+                .map(|(i, p)| (p.id().to_string(), i))
+                .collect();
+            TestRepo {
+                repo_id,
+                url,
+                aliases: vec![],
+                packages,
+                package_ids,
+            }
+        }
+
+        fn view(&self) -> RepoPackages<'_> {
+            RepoPackages {
+                repo_id: self.repo_id,
+                url: self.url,
+                aliases: &self.aliases,
+                packages: &self.packages,
+                package_ids: &self.package_ids,
+            }
+        }
+    }
+
+    fn target(package: &Package) -> &Target {
+        match package {
+            Package::Concrete(v) => &v.release[0].target[0],
+            _ => panic!("not a concrete package"),
+        }
+    }
+
+    /// `main` depends on `tools` by URL; `tools` used to live at `old.example`.
+    fn hosted() -> Vec<TestRepo> {
+        let main = TestRepo::new(
+            "main",
+            "https://pahkat.example/main",
+            vec![
+                package(
+                    "speller-sme",
+                    &["https://pahkat.example/tools/packages/windivvun"],
+                ),
+                package(
+                    "speller-smj",
+                    &["https://old.example/tools/packages/windivvun/?platform=windows"],
+                ),
+                package("speller-sma", &["windivvun"]),
+            ],
+        );
+        let mut tools = TestRepo::new(
+            "tools",
+            "https://pahkat.example/tools/",
+            vec![
+                package("windivvun", &[]),
+                package("divvun-manager", &["windivvun"]),
+            ],
+        );
+        tools.aliases = vec!["https://old.example/tools".to_string()];
+        vec![main, tools]
+    }
+
+    #[test]
+    fn parses_bare_ids() {
+        assert_eq!(
+            parse_dependency_key("windivvun"),
+            DependencyRef {
+                repo_url: None,
+                package_id: "windivvun"
+            }
+        );
+    }
+
+    #[test]
+    fn parses_full_package_urls() {
+        let expected = DependencyRef {
+            repo_url: Some("https://pahkat.example/tools"),
+            package_id: "windivvun",
+        };
+
+        for key in [
+            "https://pahkat.example/tools/packages/windivvun",
+            "https://pahkat.example/tools/packages/windivvun/",
+            "https://pahkat.example/tools/packages/windivvun?platform=windows",
+            "https://pahkat.example/tools/packages/windivvun/?platform=windows",
+            "https://pahkat.example/tools/packages/windivvun#latest",
+            "https://pahkat.example/tools//packages/windivvun",
+        ] {
+            assert_eq!(parse_dependency_key(key), expected, "{}", key);
+        }
+    }
+
+    #[test]
+    fn locates_by_url_alias_and_bare_id() {
+        let hosted = hosted();
+        let repos = hosted.iter().map(TestRepo::view).collect::<Vec<_>>();
+
+        for key in [
+            "https://pahkat.example/tools/packages/windivvun",
+            "https://pahkat.example/tools/packages/windivvun/",
+            "https://old.example/tools/packages/windivvun",
+            "https://old.example/tools/packages/windivvun/?platform=windows",
+        ] {
+            let (repo, package) = locate(&repos, "main", key).expect(key);
+            assert_eq!((repo.repo_id, package.id()), ("tools", "windivvun"));
+        }
+
+        let (repo, _) = locate(&repos, "tools", "windivvun").unwrap();
+        assert_eq!(repo.repo_id, "tools");
+
+        // Bare ids only ever refer to the depending package's own repo
+        assert!(locate(&repos, "main", "windivvun").is_none());
+    }
+
+    #[test]
+    fn reports_unknown_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosted = hosted();
+        let repos = hosted.iter().map(TestRepo::view).collect::<Vec<_>>();
+
+        let known = package(
+            "speller-sme",
+            &[
+                "https://pahkat.example/tools/packages/windivvun/",
+                "https://old.example/tools/packages/divvun-manager",
+                "speller-smj",
+            ],
+        );
+        assert!(unknown_dependencies(&repos, dir.path(), "main", target(&known)).is_empty());
+
+        let unknown = package(
+            "speller-sme",
+            &[
+                "windivvun",
+                "https://pahkat.example/tools/packages/missing",
+                "https://elsewhere.example/tools/packages/windivvun",
+            ],
+        );
+        let mut found = unknown_dependencies(&repos, dir.path(), "main", target(&unknown));
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                "https://elsewhere.example/tools/packages/windivvun",
+                "https://pahkat.example/tools/packages/missing",
+                "windivvun",
+            ]
+        );
+    }
+
+    #[test]
+    fn unindexed_packages_in_the_working_tree_are_known() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosted = hosted();
+        let repos = hosted.iter().map(TestRepo::view).collect::<Vec<_>>();
+
+        let package_path = dir.path().join("tools").join("packages").join("fresh");
+        std::fs::create_dir_all(&package_path).unwrap();
+        std::fs::write(package_path.join("index.toml"), "").unwrap();
+
+        let depending = package(
+            "speller-sme",
+            &["https://old.example/tools/packages/fresh/", "fresh"],
+        );
+        assert_eq!(
+            unknown_dependencies(&repos, dir.path(), "main", target(&depending)),
+            ["fresh"]
+        );
+    }
+
+    #[test]
+    fn lists_reverse_dependencies_across_repos() {
+        let hosted = hosted();
+        let repos = hosted.iter().map(TestRepo::view).collect::<Vec<_>>();
+
+        let found = reverse_dependencies(&repos, "tools", "windivvun")
+            .into_iter()
+            .map(|x| format!("{}/{}", x.repo_id, x.package_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                "main/speller-sme",
+                "main/speller-smj",
+                "tools/divvun-manager"
+            ]
+        );
+
+        assert!(reverse_dependencies(&repos, "main", "windivvun").is_empty());
+        assert!(reverse_dependencies(&repos, "tools", "divvun-manager").is_empty());
+    }
+
+    const WINDOWS: ReleaseQuery<'static> = ReleaseQuery {
+        platform: "windows",
+        arch: None,
+        channel: None,
+        version: None,
+    };
+
+    fn resolved(repos: &[RepoPackages<'_>], repo_id: &str, package_id: &str) -> Vec<String> {
+        resolve(repos, repo_id, package_id, WINDOWS)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|x| format!("{}/{}", x.repo_id, x.package_id))
+            .collect()
+    }
+
+    fn cycle(repos: &[RepoPackages<'_>], repo_id: &str, package_id: &str) -> Vec<String> {
+        match resolve(repos, repo_id, package_id, WINDOWS) {
+            Err(ResolveError::Cycle(v)) => v,
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn resolves_dependencies_first() {
+        let hosted = hosted();
+        let repos = hosted.iter().map(TestRepo::view).collect::<Vec<_>>();
+
+        assert_eq!(
+            resolved(&repos, "main", "speller-smj"),
+            ["tools/windivvun", "main/speller-smj"]
+        );
+        assert_eq!(resolved(&repos, "tools", "windivvun"), ["tools/windivvun"]);
+    }
+
+    #[test]
+    fn diamonds_are_not_cycles() {
+        let main = TestRepo::new(
+            "main",
+            "https://pahkat.example/main",
+            vec![
+                package("app", &["left", "right"]),
+                package("left", &["https://pahkat.example/tools/packages/base"]),
+                package("right", &["https://pahkat.example/tools/packages/base"]),
+            ],
+        );
+        let tools = TestRepo::new(
+            "tools",
+            "https://pahkat.example/tools",
+            vec![package("base", &[])],
+        );
+        let repos = [main.view(), tools.view()];
+
+        assert_eq!(
+            resolved(&repos, "main", "app"),
+            ["tools/base", "main/left", "main/right", "main/app"]
+        );
+    }
+
+    #[test]
+    fn reports_direct_cycles() {
+        let main = TestRepo::new(
+            "main",
+            "https://pahkat.example/main",
+            vec![package("app", &["app"])],
+        );
+        let repos = [main.view()];
+
+        assert_eq!(cycle(&repos, "main", "app"), ["main/app", "main/app"]);
+    }
+
+    #[test]
+    fn reports_indirect_cycles_across_repos() {
+        let main = TestRepo::new(
+            "main",
+            "https://pahkat.example/main",
+            vec![
+                package("app", &["lib"]),
+                package("lib", &["https://pahkat.example/tools/packages/helper"]),
+            ],
+        );
+        let tools = TestRepo::new(
+            "tools",
+            "https://pahkat.example/tools",
+            vec![package(
+                "helper",
+                &["https://pahkat.example/main/packages/lib"],
+            )],
+        );
+        let repos = [main.view(), tools.view()];
+
+        assert_eq!(
+            cycle(&repos, "main", "app"),
+            ["main/lib", "tools/helper", "main/lib"]
+        );
+    }
+
+    #[test]
+    fn reports_missing_dependencies() {
+        let main = TestRepo::new(
+            "main",
+            "https://pahkat.example/main",
+            vec![
+                package("app", &["lib"]),
+                package("lib", &["https://elsewhere.example/tools/packages/helper"]),
+                package("tool", &["missing"]),
+            ],
+        );
+        let repos = [main.view()];
+
+        match resolve(&repos, "main", "app", WINDOWS) {
+            Err(ResolveError::MissingPackage { key, required_by }) => {
+                assert_eq!(key, "https://elsewhere.example/tools/packages/helper");
+                assert_eq!(required_by, "main/lib");
+            }
+            other => panic!("expected a missing package, got {:?}", other),
+        }
+
+        match resolve(&repos, "main", "tool", WINDOWS) {
+            Err(ResolveError::MissingPackage { key, required_by }) => {
+                assert_eq!(key, "missing");
+                assert_eq!(required_by, "main/tool");
+            }
+            other => panic!("expected a missing package, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_repos_and_packages_resolve_to_none() {
+        let hosted = hosted();
+        let repos = hosted.iter().map(TestRepo::view).collect::<Vec<_>>();
+
+        assert!(resolve(&repos, "main", "missing", WINDOWS)
+            .unwrap()
+            .is_none());
+        assert!(resolve(&repos, "missing", "windivvun", WINDOWS)
+            .unwrap()
+            .is_none());
+        // Hosted, but in another repo
+        assert!(resolve(&repos, "main", "windivvun", WINDOWS)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn reports_packages_without_a_matching_release() {
+        let hosted = hosted();
+        let repos = hosted.iter().map(TestRepo::view).collect::<Vec<_>>();
+        let query = ReleaseQuery {
+            platform: "macos",
+            ..WINDOWS
+        };
+
+        assert!(matches!(
+            resolve(&repos, "tools", "windivvun", query),
+            Err(ResolveError::NoMatchingRelease(key)) if key == "tools/windivvun"
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+use std::io::Write;
+
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+
+/// Content codings the server can serve precompressed bodies in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContentEncoding {
+    Identity,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// Preferred first when a client accepts several with the same quality.
+    const PREFERENCE: [ContentEncoding; 4] = [
+        ContentEncoding::Brotli,
+        ContentEncoding::Zstd,
+        ContentEncoding::Gzip,
+        ContentEncoding::Identity,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Zstd => "zstd",
+        }
+    }
+
+    /// Picks the best coding allowed by an `Accept-Encoding` header.
+    ///
+    /// Identity is used when nothing else is acceptable, even if the client
+    /// ruled it out, as a `406` helps nobody fetching an index.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Self {
+        let accept_encoding = match accept_encoding {
+            Some(v) => v,
+            None => return ContentEncoding::Identity,
+        };
+
+        let mut accepted: Vec<(&str, f32)> = vec![];
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';').map(|x| x.trim());
+            let coding = match parts.next() {
+                Some(v) if !v.is_empty() => v,
+                _ => continue,
+            };
+            let quality = parts
+                .find_map(|x| x.strip_prefix("q="))
+                .and_then(|x| x.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            accepted.push((coding, quality));
+        }
+
+        let quality_of = |encoding: ContentEncoding| {
+            accepted
+                .iter()
+                .find(|(coding, _)| coding.eq_ignore_ascii_case(encoding.as_str()))
+                .or_else(|| accepted.iter().find(|(coding, _)| *coding == "*"))
+                .map(|(_, q)| *q)
+                .unwrap_or(0.0)
+        };
+
+        let mut best = ContentEncoding::Identity;
+        let mut best_quality = 0.0;
+        for encoding in Self::PREFERENCE {
+            let quality = quality_of(encoding);
+            if quality > best_quality {
+                best = encoding;
+                best_quality = quality;
+            }
+        }
+        best
+    }
+}
+
+/// A response body along with its precomputed compressed forms.
+#[derive(Debug)]
+pub(crate) struct EncodedBody {
+    identity: Bytes,
+    gzip: Bytes,
+    brotli: Bytes,
+    zstd: Bytes,
+    /// Hash of the uncompressed body, shared by all encodings' ETags
+    hash: String,
+}
+
+impl EncodedBody {
+    pub fn new(data: Vec<u8>) -> Result<Self, std::io::Error> {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::best());
+        gzip.write_all(&data)?;
+        let gzip = gzip.finish()?;
+
+        let mut brotli = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut brotli, 4096, 11, 22);
+            writer.write_all(&data)?;
+        }
+
+        let zstd = zstd::stream::encode_all(&data[..], 19)?;
+
+        let hash = Sha256::digest(&data);
+
+        Ok(Self {
+            hash: hex::encode(&hash[..16]),
+            identity: Bytes::from(data),
+            gzip: Bytes::from(gzip),
+            brotli: Bytes::from(brotli),
+            zstd: Bytes::from(zstd),
+        })
+    }
+
+    /// The body in `encoding`; cloning the result does not copy the data.
+    pub fn get(&self, encoding: ContentEncoding) -> &Bytes {
+        match encoding {
+            ContentEncoding::Identity => &self.identity,
+            ContentEncoding::Gzip => &self.gzip,
+            ContentEncoding::Brotli => &self.brotli,
+            ContentEncoding::Zstd => &self.zstd,
+        }
+    }
+
+    /// A strong ETag for the body in `encoding`.
+    pub fn etag(&self, encoding: ContentEncoding) -> String {
+        match encoding {
+            ContentEncoding::Identity => format!("\"{}\"", self.hash),
+            encoding => format!("\"{}-{}\"", self.hash, encoding.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiate(accept_encoding: &str) -> ContentEncoding {
+        ContentEncoding::negotiate(Some(accept_encoding))
+    }
+
+    #[test]
+    fn without_accept_encoding_uses_identity() {
+        assert_eq!(ContentEncoding::negotiate(None), ContentEncoding::Identity);
+        assert_eq!(negotiate(""), ContentEncoding::Identity);
+    }
+
+    #[test]
+    fn highest_quality_wins() {
+        assert_eq!(negotiate("gzip;q=0.8, br;q=0.5"), ContentEncoding::Gzip);
+        assert_eq!(
+            negotiate("br;q=0.1, zstd;q=0.9, gzip"),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            negotiate("gzip ; q=0.2, zstd ; q=0.3"),
+            ContentEncoding::Zstd
+        );
+    }
+
+    #[test]
+    fn codings_are_case_insensitive() {
+        assert_eq!(negotiate("GZIP"), ContentEncoding::Gzip);
+        assert_eq!(negotiate("Br"), ContentEncoding::Brotli);
+    }
+
+    #[test]
+    fn zero_quality_excludes_a_coding() {
+        assert_eq!(negotiate("br;q=0, gzip"), ContentEncoding::Gzip);
+        assert_eq!(negotiate("*, br;q=0"), ContentEncoding::Zstd);
+        assert_eq!(
+            negotiate("*;q=0.5, br;q=0, zstd;q=0"),
+            ContentEncoding::Gzip
+        );
+    }
+
+    #[test]
+    fn wildcard_covers_unlisted_codings() {
+        assert_eq!(negotiate("*"), ContentEncoding::Brotli);
+        assert_eq!(negotiate("gzip, *;q=0.5"), ContentEncoding::Gzip);
+        assert_eq!(negotiate("gzip;q=0.4, *;q=0.5"), ContentEncoding::Brotli);
+    }
+
+    #[test]
+    fn falls_back_to_identity_when_nothing_else_is_acceptable() {
+        assert_eq!(negotiate("compress, deflate"), ContentEncoding::Identity);
+        assert_eq!(negotiate("gzip;q=0"), ContentEncoding::Identity);
+        // Even when identity itself is ruled out
+        assert_eq!(negotiate("*;q=0"), ContentEncoding::Identity);
+        assert_eq!(negotiate("identity;q=0, br;q=0"), ContentEncoding::Identity);
+    }
+
+    #[test]
+    fn ties_follow_server_preference() {
+        assert_eq!(negotiate("gzip, zstd, br"), ContentEncoding::Brotli);
+        assert_eq!(negotiate("gzip, zstd"), ContentEncoding::Zstd);
+        assert_eq!(negotiate("gzip;q=0.5, zstd;q=0.5"), ContentEncoding::Zstd);
+        assert_eq!(negotiate("identity, gzip"), ContentEncoding::Gzip);
+    }
+
+    #[test]
+    fn malformed_qualities_count_as_one() {
+        assert_eq!(negotiate("gzip;q=high, br;q=0.5"), ContentEncoding::Gzip);
+    }
+}
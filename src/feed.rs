@@ -0,0 +1,347 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use once_cell::sync::Lazy;
+use pahkat_types::package::Package;
+use parking_lot::Mutex;
+use poem_openapi::Object;
+
+use crate::{git::UpdateCommit, state::GIT_REPO, Config, RepoIndexData};
+
+/// How many update commits a feed lists.
+pub(crate) const FEED_LENGTH: usize = 50;
+
+/// Distinct filters cached per repo before the cache is emptied.
+const FEED_CACHE_LENGTH: usize = 64;
+
+/// Update commits read for one `head_ref` of a repo, by package and channel filter.
+struct FeedCache {
+    head_ref: Arc<str>,
+    commits: HashMap<(Option<String>, Option<String>), Arc<[UpdateCommit]>>,
+}
+
+static FEED_COMMITS: Lazy<Mutex<HashMap<String, FeedCache>>> = Lazy::new(Default::default);
+
+/// A published release, built from an update commit and the current index.
+#[derive(Debug, Clone)]
+pub(crate) struct FeedEntry {
+    commit: String,
+    timestamp: DateTime<FixedOffset>,
+    package_id: String,
+    name: String,
+    version: String,
+    channel: String,
+    platforms: Vec<String>,
+    download_url: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FeedFilter<'a> {
+    pub package_id: Option<&'a str>,
+    pub channel: Option<&'a str>,
+    pub lang: Option<&'a str>,
+}
+
+impl FeedFilter<'_> {
+    pub fn matches(&self, commit: &UpdateCommit) -> bool {
+        self.package_id
+            .map(|x| x == commit.package_id)
+            .unwrap_or(true)
+            && self.channel.map(|x| x == commit.channel).unwrap_or(true)
+    }
+}
+
+fn localized_name(package: &Package, lang: &str) -> Option<String> {
+    let descriptor = match package {
+        Package::Concrete(v) => v,
+        _ => return None,
+    };
+
+    descriptor
+        .name
+        .get(lang)
+        .or_else(|| descriptor.name.get("en"))
+        .or_else(|| descriptor.name.values().next())
+        .cloned()
+}
+
+fn release_platforms(package: &Package, commit: &UpdateCommit) -> Vec<String> {
+    let descriptor = match package {
+        Package::Concrete(v) => v,
+        _ => return vec![],
+    };
+
+    let mut platforms = descriptor
+        .release
+        .iter()
+        .filter(|r| r.version.to_string() == commit.version)
+        .filter(|r| r.channel.as_deref().unwrap_or("stable") == commit.channel)
+        .flat_map(|r| r.target.iter().map(|t| t.platform.clone()))
+        .collect::<Vec<_>>();
+    platforms.sort();
+    platforms.dedup();
+    platforms
+}
+
+/// The update commits of a repo up to `head_ref` that `filter` accepts.
+///
+/// The log does not change for a given `head_ref`, so it is read once per
+/// filter on a blocking thread and then served from memory.
+pub(crate) async fn update_commits(
+    repo_id: &str,
+    head_ref: &Arc<str>,
+    filter: &FeedFilter<'_>,
+) -> Result<Arc<[UpdateCommit]>, std::io::Error> {
+    let key = (
+        filter.package_id.map(str::to_string),
+        filter.channel.map(str::to_string),
+    );
+
+    let cached = FEED_COMMITS
+        .lock()
+        .get(repo_id)
+        .filter(|x| x.head_ref == *head_ref)
+        .and_then(|x| x.commits.get(&key).cloned());
+    if let Some(commits) = cached {
+        return Ok(commits);
+    }
+
+    let commits = {
+        let repo_id = repo_id.to_string();
+        let head_ref = Arc::clone(head_ref);
+        let (package_id, channel) = key.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let filter = FeedFilter {
+                package_id: package_id.as_deref(),
+                channel: channel.as_deref(),
+                lang: None,
+            };
+            GIT_REPO
+                .get()
+                .unwrap()
+                .read()
+                .update_log(&repo_id, &head_ref, FEED_LENGTH, |x| filter.matches(x))
+        })
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??
+    };
+    let commits = Arc::<[UpdateCommit]>::from(commits);
+
+    let mut cache = FEED_COMMITS.lock();
+    let entry = cache
+        .entry(repo_id.to_string())
+        .or_insert_with(|| FeedCache {
+            head_ref: Arc::clone(head_ref),
+            commits: HashMap::new(),
+        });
+    if entry.head_ref != *head_ref {
+        entry.head_ref = Arc::clone(head_ref);
+        entry.commits.clear();
+    }
+    if entry.commits.len() >= FEED_CACHE_LENGTH {
+        entry.commits.clear();
+    }
+    entry.commits.insert(key, Arc::clone(&commits));
+
+    Ok(commits)
+}
+
+pub(crate) fn entries(
+    config: &Config,
+    repo_id: &str,
+    data: &RepoIndexData,
+    commits: &[UpdateCommit],
+    filter: &FeedFilter<'_>,
+) -> Vec<FeedEntry> {
+    let lang = filter.lang.unwrap_or("en");
+
+    commits
+        .iter()
+        .filter(|c| filter.matches(c))
+        .map(|commit| {
+            let package = data.package(&commit.package_id);
+
+            let name = package
+                .and_then(|p| localized_name(p, lang))
+                .unwrap_or_else(|| commit.package_id.clone());
+            let mut platforms = package
+                .map(|p| release_platforms(p, commit))
+                .unwrap_or_default();
+            if platforms.is_empty() {
+                platforms.push(commit.platform.clone());
+            }
+
+            FeedEntry {
+                commit: commit.hash.clone(),
+                timestamp: commit.timestamp,
+                package_id: commit.package_id.clone(),
+                name,
+                version: commit.version.clone(),
+                channel: commit.channel.clone(),
+                download_url: format!(
+                    "{}/{}/download/{}?platform={}&channel={}&version={}",
+                    config.url,
+                    repo_id,
+                    commit.package_id,
+                    commit.platform,
+                    commit.channel,
+                    commit.version
+                ),
+                platforms,
+            }
+        })
+        .collect()
+}
+
+impl FeedEntry {
+    fn id(&self, config: &Config, repo_id: &str) -> String {
+        format!(
+            "{}/{}/packages/{}#{}",
+            config.url, repo_id, self.package_id, self.commit
+        )
+    }
+
+    fn title(&self) -> String {
+        format!("{} {} ({})", self.name, self.version, self.channel)
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} {} released to {} for {}.",
+            self.name,
+            self.version,
+            self.channel,
+            self.platforms.join(", ")
+        )
+    }
+}
+
+fn escape_xml(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Renders entries as an Atom 1.0 document.
+pub(crate) fn atom(
+    config: &Config,
+    repo_id: &str,
+    self_url: &str,
+    entries: &[FeedEntry],
+) -> String {
+    let updated = entries
+        .iter()
+        .map(|e| e.timestamp.with_timezone(&Utc))
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str(&format!(
+        "  <id>{}</id>\n",
+        escape_xml(&format!("{}/{}", config.url, repo_id))
+    ));
+    out.push_str(&format!(
+        "  <title>{} releases</title>\n",
+        escape_xml(repo_id)
+    ));
+    out.push_str(&format!("  <updated>{}</updated>\n", updated.to_rfc3339()));
+    out.push_str(&format!(
+        "  <author><name>{}</name></author>\n",
+        escape_xml(repo_id)
+    ));
+    out.push_str(&format!(
+        "  <link rel=\"self\" href=\"{}\"/>\n",
+        escape_xml(self_url)
+    ));
+
+    for entry in entries {
+        out.push_str("  <entry>\n");
+        out.push_str(&format!(
+            "    <id>{}</id>\n",
+            escape_xml(&entry.id(config, repo_id))
+        ));
+        out.push_str(&format!(
+            "    <title>{}</title>\n",
+            escape_xml(&entry.title())
+        ));
+        out.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            entry.timestamp.to_rfc3339()
+        ));
+        out.push_str(&format!(
+            "    <link rel=\"alternate\" href=\"{}\"/>\n",
+            escape_xml(&entry.download_url)
+        ));
+        for platform in entry.platforms.iter() {
+            out.push_str(&format!(
+                "    <category term=\"{}\"/>\n",
+                escape_xml(platform)
+            ));
+        }
+        out.push_str(&format!(
+            "    <summary>{}</summary>\n",
+            escape_xml(&entry.summary())
+        ));
+        out.push_str("  </entry>\n");
+    }
+
+    out.push_str("</feed>\n");
+    out
+}
+
+#[derive(Object, Debug, Clone)]
+pub(crate) struct JsonFeedItem {
+    id: String,
+    url: String,
+    title: String,
+    content_text: String,
+    date_published: DateTime<Utc>,
+    tags: Vec<String>,
+}
+
+/// A JSON Feed 1.1 document.
+#[derive(Object, Debug, Clone)]
+pub(crate) struct JsonFeed {
+    version: String,
+    title: String,
+    home_page_url: String,
+    feed_url: String,
+    items: Vec<JsonFeedItem>,
+}
+
+pub(crate) fn json_feed(
+    config: &Config,
+    repo_id: &str,
+    self_url: &str,
+    entries: &[FeedEntry],
+) -> JsonFeed {
+    JsonFeed {
+        version: "https://jsonfeed.org/version/1.1".into(),
+        title: format!("{} releases", repo_id),
+        home_page_url: format!("{}/{}", config.url, repo_id),
+        feed_url: self_url.to_string(),
+        items: entries
+            .iter()
+            .map(|entry| JsonFeedItem {
+                id: entry.id(config, repo_id),
+                url: entry.download_url.clone(),
+                title: entry.title(),
+                content_text: entry.summary(),
+                date_published: entry.timestamp.with_timezone(&Utc),
+                tags: entry.platforms.clone(),
+            })
+            .collect(),
+    }
+}
@@ -1,8 +1,10 @@
 use std::{
+    io::{BufRead, BufReader},
     path::{self, PathBuf},
-    process::Command,
+    process::{Command, ExitStatus, Stdio},
 };
 
+use chrono::{DateTime, FixedOffset, Utc};
 use tempfile::TempDir;
 
 use crate::{openapi::UpdatePackageMetadataRequest, Config};
@@ -19,6 +21,65 @@ fn git_revparse_head(path: &path::Path) -> String {
         .to_string()
 }
 
+/// Turns a failed git exit status into an error, so callers do not act on a
+/// commit or push that never happened.
+fn check_status(action: &str, status: ExitStatus) -> Result<(), std::io::Error> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("git {} failed: {}", action, status),
+        ))
+    }
+}
+
+/// The committer timestamp of `HEAD` in the repository containing `path`.
+pub(crate) fn head_commit_time(path: &path::Path) -> Result<DateTime<Utc>, std::io::Error> {
+    let output = Command::new("git")
+        .args(&["show", "--no-patch", "--format=%cI", "HEAD"])
+        .current_dir(path)
+        .output()?;
+    let timestamp = String::from_utf8_lossy(&output.stdout);
+    DateTime::parse_from_rfc3339(timestamp.trim())
+        .map(|x| x.with_timezone(&Utc))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// A `[repo:update]` commit, parsed back from the message `commit_update` writes.
+#[derive(Debug, Clone)]
+pub struct UpdateCommit {
+    pub hash: String,
+    pub timestamp: DateTime<FixedOffset>,
+    pub package_id: String,
+    pub version: String,
+    pub platform: String,
+    pub channel: String,
+}
+
+impl UpdateCommit {
+    fn parse(repo_id: &str, hash: &str, timestamp: &str, subject: &str) -> Option<Self> {
+        // [repo:update] `package version platform (channel)`
+        let rest = subject.strip_prefix(&format!("[{}:update] `", repo_id))?;
+        let rest = rest.strip_suffix('`')?;
+
+        let mut parts = rest.split(' ');
+        let package_id = parts.next()?;
+        let version = parts.next()?;
+        let platform = parts.next()?;
+        let channel = parts.next()?.strip_prefix('(')?.strip_suffix(')')?;
+
+        Some(Self {
+            hash: hash.to_string(),
+            timestamp: DateTime::parse_from_rfc3339(timestamp).ok()?,
+            package_id: package_id.to_string(),
+            version: version.to_string(),
+            platform: platform.to_string(),
+            channel: channel.to_string(),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct GitRepo {
     pub(crate) path: PathBuf,
@@ -38,49 +99,136 @@ impl GitRepo {
         repo_id: &str,
         package_id: &str,
     ) -> Result<(), std::io::Error> {
-        Command::new("git")
+        let status = Command::new("git")
             .arg("add")
             .arg(format!("{}/packages/{}", repo_id, package_id))
             .current_dir(&self.path)
             .status()?;
+        check_status("add", status)?;
 
         Ok(())
     }
 
-    pub fn commit_create(&mut self, repo_id: &str, package_id: &str) -> Result<(), std::io::Error> {
-        Command::new("git")
+    /// Whether the index differs from `HEAD`, i.e. whether committing would
+    /// record anything.
+    fn has_staged_changes(&self) -> Result<bool, std::io::Error> {
+        let status = Command::new("git")
+            .args(&["diff", "--cached", "--quiet"])
+            .current_dir(&self.path)
+            .status()?;
+
+        match status.code() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("git diff failed: {}", status),
+            )),
+        }
+    }
+
+    /// Commits the staged package. Returns `false` without committing when
+    /// nothing changed, as `git commit` would fail on an empty commit.
+    pub fn commit_create(
+        &mut self,
+        repo_id: &str,
+        package_id: &str,
+    ) -> Result<bool, std::io::Error> {
+        if !self.has_staged_changes()? {
+            return Ok(false);
+        }
+
+        let status = Command::new("git")
             .args(&["commit", "-m"])
             .arg(format!("[{}:create] `{}`", repo_id, package_id))
             .current_dir(&self.path)
             .status()?;
+        check_status("commit", status)?;
 
         self.head_ref = git_revparse_head(&self.path);
 
-        Ok(())
+        Ok(true)
     }
 
+    /// Commits the staged release. Returns `false` without committing when
+    /// nothing changed, e.g. when the same metadata is published again.
     pub fn commit_update(
         &mut self,
         repo_id: &str,
         package_id: &str,
         release: &UpdatePackageMetadataRequest,
-    ) -> Result<(), std::io::Error> {
-        Command::new("git")
+    ) -> Result<bool, std::io::Error> {
+        if !self.has_staged_changes()? {
+            return Ok(false);
+        }
+
+        let status = Command::new("git")
             .args(&["commit", "-m"])
             .arg(format!("[{}:update] `{} {}`", repo_id, package_id, release))
             .current_dir(&self.path)
             .status()?;
+        check_status("commit", status)?;
 
         self.head_ref = git_revparse_head(&self.path);
 
-        Ok(())
+        Ok(true)
+    }
+
+    /// The most recent `[repo:update]` commits for a repo up to `rev`
+    /// accepted by `matches`, newest first.
+    ///
+    /// The log is read until `limit` commits match, so filtering does not
+    /// shorten the result while older matching history exists.
+    pub fn update_log(
+        &self,
+        repo_id: &str,
+        rev: &str,
+        limit: usize,
+        matches: impl Fn(&UpdateCommit) -> bool,
+    ) -> Result<Vec<UpdateCommit>, std::io::Error> {
+        let mut child = Command::new("git")
+            .args(&["log", "--fixed-strings", "--format=%H%x1f%cI%x1f%s"])
+            .arg(format!("--grep=[{}:update]", repo_id))
+            .arg(rev)
+            .arg("--")
+            .current_dir(&self.path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let mut commits = vec![];
+        for line in BufReader::new(stdout).lines() {
+            if commits.len() >= limit {
+                break;
+            }
+
+            let line = line?;
+            let mut fields = line.splitn(3, '\x1f');
+            let commit = match (fields.next(), fields.next(), fields.next()) {
+                (Some(hash), Some(timestamp), Some(subject)) => {
+                    UpdateCommit::parse(repo_id, hash, timestamp, subject)
+                }
+                _ => None,
+            };
+            if let Some(commit) = commit.filter(|x| matches(x)) {
+                commits.push(commit);
+            }
+        }
+
+        // Stop git instead of reading the rest of the history.
+        let _ = child.kill();
+        child.wait()?;
+
+        Ok(commits)
     }
 
     pub fn push(&self, config: &Config) -> Result<(), std::io::Error> {
-        Command::new("git")
+        let status = Command::new("git")
             .args(&["push", "origin", &format!("HEAD:{}", &config.branch_name)])
             .current_dir(&self.path)
             .status()?;
+        check_status("push", status)?;
         Ok(())
     }
 
@@ -115,3 +263,64 @@ impl GitRepo {
         Ok(tmpdir)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git(path: &path::Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(args)
+            .current_dir(path)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    fn repo() -> (TempDir, GitRepo) {
+        let dir = tempfile::tempdir().unwrap();
+        git(dir.path(), &["init", "--quiet"]);
+        git(dir.path(), &["config", "user.name", "Test"]);
+        git(dir.path(), &["config", "user.email", "test@pahkat.example"]);
+        git(
+            dir.path(),
+            &["commit", "--quiet", "--allow-empty", "-m", "Initial"],
+        );
+
+        let repo = GitRepo::new(dir.path().to_path_buf());
+        (dir, repo)
+    }
+
+    fn write_package(repo: &mut GitRepo, contents: &str) {
+        let package_path = repo.path.join("main").join("packages").join("speller-sme");
+        std::fs::create_dir_all(&package_path).unwrap();
+        std::fs::write(package_path.join("index.toml"), contents).unwrap();
+        repo.add_package_to_index_tree("main", "speller-sme")
+            .unwrap();
+    }
+
+    #[test]
+    fn commits_changes() {
+        let (_dir, mut repo) = repo();
+        let initial = repo.head_ref.clone();
+
+        write_package(&mut repo, "version = 1\n");
+        assert!(repo.commit_create("main", "speller-sme").unwrap());
+        assert_ne!(repo.head_ref, initial);
+        assert_eq!(repo.head_ref, git_revparse_head(&repo.path));
+    }
+
+    #[test]
+    fn unchanged_packages_are_not_committed() {
+        let (_dir, mut repo) = repo();
+
+        write_package(&mut repo, "version = 1\n");
+        assert!(repo.commit_create("main", "speller-sme").unwrap());
+        let head_ref = repo.head_ref.clone();
+
+        write_package(&mut repo, "version = 1\n");
+        assert!(!repo.commit_create("main", "speller-sme").unwrap());
+        assert_eq!(repo.head_ref, head_ref);
+        assert_eq!(git_revparse_head(&repo.path), head_ref);
+    }
+}
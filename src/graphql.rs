@@ -1,15 +1,103 @@
 use std::sync::Arc;
 
-use arc_ext::{ArcExt, ArcProjectOption};
-use arc_swap::Guard;
-use async_graphql::Object;
-use pahkat_types::{package::Package, repo::Index};
+use arc_swap::Guard as ArcGuard;
+use async_graphql::{
+    connection::{query, Connection, Edge},
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextParseQuery},
+    futures_util::{future, stream, Stream, StreamExt},
+    parser::types::{ExecutableDocument, OperationType},
+    Context, Guard, InputObject, Json, Object, ServerError, ServerResult, SimpleObject,
+    Subscription, Variables,
+};
+use chrono::NaiveDate;
+use pahkat_types::{
+    package::{Descriptor, Package, Release},
+    payload::Target,
+    repo::Index,
+    LangTagMap,
+};
 
 use crate::{
-    state::{ServerStatus, REPO_INDEXES, SERVER_STATUS},
-    RepoIndexData,
+    checksums::PayloadChecksum,
+    deps::{self, ResolvedPackage},
+    openapi::{
+        CreatePackageMetadataRequest, UpdatePackageMetadataRequest, DEFAULT_PAGE_LIMIT,
+        MAX_PAGE_LIMIT,
+    },
+    publish,
+    release::{self, ReleaseQuery},
+    search::{PackageFilter, SearchQuery},
+    signing::{self, PublicKeyInfo},
+    state::{
+        IndexChanged, ServerStatus, DOWNLOAD_STATS, INDEX_EVENTS, REPO_INDEXES, SERVER_STATUS,
+        SIGNER,
+    },
+    stats::PackageDownloads,
+    Config, RepoIndexData,
 };
 
+/// The bearer token sent with a GraphQL request, if any.
+pub(crate) struct BearerToken(pub(crate) String);
+
+/// Only allows requests carrying the configured API token.
+struct TokenGuard;
+
+#[async_graphql::async_trait::async_trait]
+impl Guard for TokenGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        let config = ctx.data::<Config>()?;
+        match ctx.data_opt::<BearerToken>() {
+            Some(BearerToken(token)) if token == &config.api_token => Ok(()),
+            _ => Err("Unauthorized".into()),
+        }
+    }
+}
+
+/// Marks a request received over GET, which may only run queries.
+pub(crate) struct GetRequest;
+
+/// The error returned for mutations and subscriptions sent over GET.
+pub(crate) const QUERY_ONLY_ERROR: &str = "Only queries may be sent with GET";
+
+/// Rejects documents with non-query operations in requests marked with
+/// `GetRequest`.
+///
+/// This runs once persisted queries have been resolved, so a hash sent over
+/// GET cannot smuggle in a mutation registered earlier over POST.
+pub(crate) struct QueryOnlyOverGet;
+
+impl ExtensionFactory for QueryOnlyOverGet {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(QueryOnlyOverGet)
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Extension for QueryOnlyOverGet {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+        if ctx.data_opt::<GetRequest>().is_none() {
+            return Ok(document);
+        }
+
+        let query_only = document
+            .operations
+            .iter()
+            .all(|(_, op)| op.node.ty == OperationType::Query);
+        if query_only {
+            Ok(document)
+        } else {
+            Err(ServerError::new(QUERY_ONLY_ERROR, None))
+        }
+    }
+}
+
 pub struct Query;
 
 #[Object]
@@ -22,8 +110,9 @@ impl Query {
         REPO_INDEXES
             .get()
             .unwrap()
-            .values()
-            .map(|value| Repo {
+            .iter()
+            .map(|(id, value)| Repo {
+                id: id.clone(),
                 model: value.load(),
             })
             .collect()
@@ -32,29 +121,580 @@ impl Query {
     async fn repo(&self, id: String) -> Option<Repo> {
         REPO_INDEXES.get().unwrap().get(&id).map(|value| Repo {
             model: value.load(),
+            id,
         })
     }
+
+    /// Packages across all repos matching every term of `query`, best match first
+    ///
+    /// Names and descriptions are only searched in `languages`, if given.
+    #[graphql(complexity = "limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT as i32)
+        .clamp(0, MAX_PAGE_LIMIT as i32) as usize
+        * child_complexity")]
+    async fn search(
+        &self,
+        query: String,
+        #[graphql(default)] platforms: Vec<String>,
+        #[graphql(default)] tags: Vec<String>,
+        #[graphql(default)] languages: Vec<String>,
+        limit: Option<i32>,
+    ) -> Vec<SearchHit> {
+        let search = SearchQuery {
+            text: query,
+            platforms,
+            tags,
+            languages,
+        };
+        let limit = limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT as i32)
+            .clamp(0, MAX_PAGE_LIMIT as i32) as usize;
+
+        let repos = REPO_INDEXES
+            .get()
+            .unwrap()
+            .iter()
+            .map(|(repo_id, state)| (repo_id.as_str(), state.load_full()));
+        rank_hits(repos, &search, limit)
+    }
+}
+
+/// The packages of `repos` matching `search`, best match first. Equal scores
+/// are ordered by repo and then package id, so results are stable.
+fn rank_hits<'a>(
+    repos: impl Iterator<Item = (&'a str, Arc<RepoIndexData>)>,
+    search: &SearchQuery,
+    limit: usize,
+) -> Vec<SearchHit> {
+    let mut hits = vec![];
+    for (repo_id, model) in repos {
+        for (index, package) in model.packages.iter().enumerate() {
+            let score = match search.score(package) {
+                Some(v) => v,
+                None => continue,
+            };
+            if let Some(package) = PackageNode::new(repo_id, Arc::clone(&model), index) {
+                hits.push(SearchHit {
+                    repo_id: repo_id.to_string(),
+                    score,
+                    package,
+                });
+            }
+        }
+    }
+
+    hits.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then_with(|| a.repo_id.cmp(&b.repo_id))
+            .then_with(|| a.package.id().cmp(b.package.id()))
+    });
+    hits.truncate(limit);
+    hits
 }
 
 struct Repo {
-    model: Guard<Arc<RepoIndexData>>,
+    id: String,
+    model: ArcGuard<Arc<RepoIndexData>>,
 }
 
 #[Object]
 impl Repo {
+    async fn id(&self) -> &str {
+        &self.id
+    }
+
     #[graphql(flatten)]
     async fn _index(&self) -> Arc<Index> {
         self.model.repo_index.clone()
     }
 
+    /// Every package in the repo, unfiltered
+    #[graphql(deprecation = "Use `packagesConnection`, which pages and filters the packages")]
     async fn packages(&self) -> Arc<[Package]> {
         self.model.packages.clone()
     }
 
-    async fn package(&self, id: String) -> ArcProjectOption<[Package], Package> {
-        self.model
+    /// Packages matching every given filter, in index order
+    ///
+    /// Without `first` or `last`, at most 50 packages are returned per page.
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(complexity = "first
+        .or(last)
+        .unwrap_or(DEFAULT_PAGE_LIMIT as i32)
+        .clamp(0, MAX_PAGE_LIMIT as i32) as usize
+        * child_complexity")]
+    async fn packages_connection(
+        &self,
+        tag: Option<String>,
+        platform: Option<String>,
+        channel: Option<String>,
+        query: Option<String>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<Connection<usize, PackageNode, PackageConnectionFields>> {
+        let filter = PackageFilter {
+            tag,
+            platform,
+            channel,
+            query,
+        };
+        let matching = self
+            .model
             .packages
-            .clone()
-            .project_option(|packages| packages.iter().find(|p| id == p.id()))
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| filter.matches(p))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let model = Arc::clone(&self.model);
+        let repo_id = self.id.clone();
+
+        let (first, last) = match (first, last) {
+            (None, None) => (Some(DEFAULT_PAGE_LIMIT as i32), None),
+            (first, last) => (
+                first.map(|x| x.min(MAX_PAGE_LIMIT as i32)),
+                last.map(|x| x.min(MAX_PAGE_LIMIT as i32)),
+            ),
+        };
+
+        query(
+            after,
+            before,
+            first,
+            last,
+            |after: Option<usize>, before: Option<usize>, first, last| async move {
+                let mut start = after.map(|x| x + 1).unwrap_or(0).min(matching.len());
+                let mut end = before
+                    .unwrap_or(matching.len())
+                    .clamp(start, matching.len());
+                if let Some(first) = first {
+                    end = (start + first).min(end);
+                }
+                if let Some(last) = last {
+                    start = end.saturating_sub(last).max(start);
+                }
+
+                let mut connection = Connection::with_additional_fields(
+                    start > 0,
+                    end < matching.len(),
+                    PackageConnectionFields {
+                        total_count: matching.len(),
+                    },
+                );
+                connection.edges.extend((start..end).map(|cursor| {
+                    Edge::new(
+                        cursor,
+                        PackageNode {
+                            repo_id: repo_id.clone(),
+                            model: Arc::clone(&model),
+                            index: matching[cursor],
+                        },
+                    )
+                }));
+                Ok::<_, async_graphql::Error>(connection)
+            },
+        )
+        .await
+    }
+
+    async fn package(&self, id: String) -> Option<PackageNode> {
+        let index = *self.model.package_ids.get(&id)?;
+        PackageNode::new(&self.id, Arc::clone(&self.model), index)
+    }
+
+    /// Keys that indexes are signed with, current key first
+    async fn signing_keys(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<PublicKeyInfo>> {
+        let config = ctx.data::<Config>()?;
+        Ok(signing::public_keys(
+            SIGNER.get().and_then(|x| x.as_ref()),
+            &config.retired_public_keys,
+        ))
+    }
+
+    /// The releases to install for a package and its dependencies, dependencies first
+    async fn resolve(
+        &self,
+        ctx: &Context<'_>,
+        package_id: String,
+        platform: String,
+        arch: Option<String>,
+        channel: Option<String>,
+    ) -> async_graphql::Result<Option<Vec<ResolvedPackage>>> {
+        let config = ctx.data::<Config>()?;
+        let query = ReleaseQuery {
+            platform: &platform,
+            arch: arch.as_deref(),
+            channel: channel.as_deref(),
+            version: None,
+        };
+
+        Ok(deps::with_hosted_repos(config, |repos| {
+            deps::resolve(repos, &self.id, &package_id, query)
+        })?)
+    }
+}
+
+#[derive(SimpleObject)]
+struct PackageConnectionFields {
+    /// Number of packages matching the filters, across all pages
+    total_count: usize,
+}
+
+/// A package descriptor from a repo index.
+struct PackageNode {
+    repo_id: String,
+    model: Arc<RepoIndexData>,
+    index: usize,
+}
+
+impl PackageNode {
+    fn new(repo_id: &str, model: Arc<RepoIndexData>, index: usize) -> Option<Self> {
+        match model.packages.get(index) {
+            Some(Package::Concrete(_)) => Some(PackageNode {
+                repo_id: repo_id.to_string(),
+                model,
+                index,
+            }),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> &str {
+        self.model.packages[self.index].id()
+    }
+
+    fn descriptor(&self) -> &Descriptor {
+        match &self.model.packages[self.index] {
+            Package::Concrete(v) => v,
+            _ => unreachable!("package nodes are only built for concrete packages"),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct SearchHit {
+    repo_id: String,
+    /// Relevance of the hit; higher is better
+    score: u32,
+    package: PackageNode,
+}
+
+#[derive(SimpleObject)]
+struct LatestRelease {
+    version: String,
+    release: Release,
+    target: Target,
+    checksum: Option<PayloadChecksum>,
+}
+
+#[Object]
+impl PackageNode {
+    #[graphql(flatten)]
+    async fn _descriptor(&self) -> &Descriptor {
+        self.descriptor()
+    }
+
+    /// The highest version release with a target for the given platform
+    async fn latest_release(
+        &self,
+        platform: String,
+        arch: Option<String>,
+        channel: Option<String>,
+    ) -> Option<LatestRelease> {
+        let query = ReleaseQuery {
+            platform: &platform,
+            arch: arch.as_deref(),
+            channel: channel.as_deref(),
+            version: None,
+        };
+
+        release::select_release(self.descriptor(), &query).map(|(release, target)| LatestRelease {
+            version: release.version.to_string(),
+            release: release.clone(),
+            target: target.clone(),
+            checksum: self.model.checksum(self.id(), release, target),
+        })
+    }
+
+    /// Daily download counts (requires the API token)
+    ///
+    /// `since` is an inclusive UTC day in `YYYY-MM-DD` format.
+    #[graphql(guard = "TokenGuard")]
+    async fn download_stats(
+        &self,
+        since: Option<String>,
+    ) -> async_graphql::Result<PackageDownloads> {
+        let since = since
+            .map(|x| NaiveDate::parse_from_str(&x, "%Y-%m-%d"))
+            .transpose()?;
+        Ok(DOWNLOAD_STATS
+            .get()
+            .unwrap()
+            .package(&self.repo_id, self.id(), since))
+    }
+}
+
+#[derive(InputObject)]
+struct CreatePackageInput {
+    name: Json<LangTagMap<String>>,
+    description: Json<LangTagMap<String>>,
+    #[graphql(default)]
+    tags: Vec<String>,
+}
+
+#[derive(InputObject)]
+struct UpdatePackageInput {
+    name: Option<Json<LangTagMap<String>>>,
+    description: Option<Json<LangTagMap<String>>>,
+    version: String,
+    channel: Option<String>,
+    #[graphql(default)]
+    authors: Vec<String>,
+    license: Option<String>,
+    license_url: Option<String>,
+    target: Json<Target>,
+    /// Hex encoded SHA-256 of the payload
+    sha256: Option<String>,
+    /// Hex encoded BLAKE3 of the payload
+    blake3: Option<String>,
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Create a package (requires the API token)
+    #[graphql(guard = "TokenGuard")]
+    async fn create_package(
+        &self,
+        ctx: &Context<'_>,
+        repo_id: String,
+        package_id: String,
+        input: CreatePackageInput,
+    ) -> async_graphql::Result<Package> {
+        let config = ctx.data::<Config>()?;
+        let request = CreatePackageMetadataRequest {
+            name: input.name.0,
+            description: input.description.0,
+            tags: input.tags,
+        };
+
+        Ok(publish::create_package(
+            config,
+            &repo_id,
+            &package_id,
+            &request,
+        )?)
+    }
+
+    /// Add or replace a release of a package (requires the API token)
+    #[graphql(guard = "TokenGuard")]
+    async fn update_package(
+        &self,
+        ctx: &Context<'_>,
+        repo_id: String,
+        package_id: String,
+        input: UpdatePackageInput,
+    ) -> async_graphql::Result<Package> {
+        let config = ctx.data::<Config>()?;
+        let request = UpdatePackageMetadataRequest {
+            name: input.name.map(|x| x.0),
+            description: input.description.map(|x| x.0),
+            version: input.version,
+            channel: input.channel,
+            authors: input.authors,
+            license: input.license,
+            license_url: input.license_url,
+            target: input.target.0,
+            sha256: input.sha256,
+            blake3: input.blake3,
+        };
+
+        Ok(publish::update_package(config, &repo_id, &package_id, &request).await?)
+    }
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// Emitted whenever a new commit adds, removes or modifies packages in a repo
+    async fn index_changed(&self, repo_id: Option<String>) -> impl Stream<Item = IndexChanged> {
+        let rx = INDEX_EVENTS.subscribe();
+
+        stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::warn!("Index change subscriber lagged, skipped {} events", n);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .filter(move |event| {
+            future::ready(
+                repo_id
+                    .as_deref()
+                    .map(|x| x == event.repo_id())
+                    .unwrap_or(true),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{Request, Schema};
+
+    use super::*;
+
+    async fn execute_over_get(query: &str) -> async_graphql::Response {
+        let schema = Schema::build(Query, Mutation, Subscription)
+            .extension(QueryOnlyOverGet)
+            .finish();
+        schema.execute(Request::new(query).data(GetRequest)).await
+    }
+
+    #[tokio::test]
+    async fn get_allows_queries() {
+        let response = execute_over_get("{ __typename }").await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+    }
+
+    #[tokio::test]
+    async fn get_rejects_mutations() {
+        let response = execute_over_get("mutation { createPackage { id } }").await;
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].message, QUERY_ONLY_ERROR);
+    }
+
+    #[tokio::test]
+    async fn get_rejects_documents_mixing_in_a_mutation() {
+        let response = execute_over_get("query A { __typename } mutation B { __typename }").await;
+        assert_eq!(response.errors[0].message, QUERY_ONLY_ERROR);
+    }
+
+    fn package(id: &str, name: &str, description: &str, tags: &[&str]) -> Package {
+        let descriptor = format!(
+            r#"
+[package]
+id = "{id}"
+tags = {tags:?}
+
+[name]
+en = "{name}"
+
+[description]
+en = "{description}"
+
+[[release]]
+version = "1.0.0"
+authors = []
+
+[[release.target]]
+platform = "windows"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "TarballPackage"
+url = "https://pahkat.example/artifacts/{id}.txz"
+size = 1
+installed_size = 1
+"#,
+            id = id,
+            name = name,
+            description = description,
+            tags = tags
+        );
+        Package::Concrete(::toml::from_str::<Descriptor>(&descriptor).unwrap())
+    }
+
+    fn repos() -> Vec<(&'static str, Arc<RepoIndexData>)> {
+        let main = vec![
+            package(
+                "speller-sme",
+                "Northern Sami speller",
+                "Spell checker",
+                &["lang:sme"],
+            ),
+            package("sme", "Keyboard", "Keyboard layout", &[]),
+            package("speller-fi", "Finnish speller", "Does not cover sme", &[]),
+            package("keyboard-fi", "Finnish keyboard", "Keyboard layout", &[]),
+        ];
+        let tools = vec![
+            package("sme-tools", "SME", "Tools", &[]),
+            package(
+                "speller-sme",
+                "Northern Sami speller",
+                "Spell checker",
+                &["lang:sme"],
+            ),
+        ];
+        vec![
+            (
+                "tools",
+                Arc::new(RepoIndexData::for_tests("tools", "a", tools)),
+            ),
+            (
+                "main",
+                Arc::new(RepoIndexData::for_tests("main", "b", main)),
+            ),
+        ]
+    }
+
+    fn search(text: &str, limit: usize) -> Vec<(String, String, u32)> {
+        let query = SearchQuery {
+            text: text.to_string(),
+            ..Default::default()
+        };
+        rank_hits(repos().into_iter(), &query, limit)
+            .into_iter()
+            .map(|x| (x.repo_id.clone(), x.package.id().to_string(), x.score))
+            .collect()
+    }
+
+    fn hit(repo_id: &str, package_id: &str, score: u32) -> (String, String, u32) {
+        (repo_id.to_string(), package_id.to_string(), score)
+    }
+
+    #[test]
+    fn search_ranks_best_matches_first() {
+        assert_eq!(
+            search("sme", 10),
+            [
+                // Partial id match plus exact name match
+                hit("tools", "sme-tools", 120),
+                // Exact id match
+                hit("main", "sme", 100),
+                // Partial id match plus tag; ties ordered by repo
+                hit("main", "speller-sme", 60),
+                hit("tools", "speller-sme", 60),
+                // Description only
+                hit("main", "speller-fi", 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn search_requires_every_term_and_respects_the_limit() {
+        assert_eq!(
+            search("SME speller", 10),
+            [
+                hit("main", "speller-sme", 130),
+                hit("tools", "speller-sme", 130),
+                hit("main", "speller-fi", 80),
+            ]
+        );
+        assert_eq!(
+            search("sme", 2),
+            [hit("tools", "sme-tools", 120), hit("main", "sme", 100)]
+        );
+        assert!(search("sme nothing", 10).is_empty());
     }
 }
@@ -0,0 +1,273 @@
+use std::{borrow::Cow, fmt::Display, ops::Deref};
+
+use poem_openapi::{
+    registry::{MetaSchema, MetaSchemaRef},
+    types::{ParseFromParameter, ParseResult, Type},
+};
+
+use crate::Config;
+
+const MAX_ID_LENGTH: usize = 128;
+
+/// Whether `value` is safe to use as a single path segment under the git
+/// checkout: ASCII letters, digits, `-`, `_` and `.`, not starting with a dot
+/// (which also rules out `.` and `..`).
+fn is_valid_segment(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_ID_LENGTH
+        && !value.starts_with('.')
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// A strings file name: a language tag followed by `.toml`, where an empty
+/// tag stands for English.
+fn is_valid_strings_file(value: &str) -> bool {
+    match value.strip_suffix(".toml") {
+        Some("") => true,
+        Some(tag) => {
+            tag.len() <= MAX_ID_LENGTH
+                && tag
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        }
+        None => false,
+    }
+}
+
+/// A value rejected by one of the id types below.
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid {kind}: `{value}`")]
+pub(crate) struct InvalidIdError {
+    kind: &'static str,
+    value: String,
+}
+
+macro_rules! impl_id_type {
+    ($(#[$docs:meta])* $ty:ident, $format:literal, $validator:expr) => {
+        $(#[$docs])*
+        #[derive(Debug, Clone, Eq, PartialEq, Hash)]
+        pub(crate) struct $ty(String);
+
+        impl $ty {
+            /// Validates `value` the same way as a URL path parameter.
+            pub fn parse(value: &str) -> Result<Self, InvalidIdError> {
+                let validator = $validator;
+                if !validator(value) {
+                    return Err(InvalidIdError {
+                        kind: $format,
+                        value: value.to_string(),
+                    });
+                }
+                Ok(Self(value.to_string()))
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Deref for $ty {
+            type Target = str;
+
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+
+        impl Display for $ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl Type for $ty {
+            const IS_REQUIRED: bool = true;
+
+            type RawValueType = Self;
+
+            type RawElementValueType = Self;
+
+            fn name() -> Cow<'static, str> {
+                concat!("string(", $format, ")").into()
+            }
+
+            fn schema_ref() -> MetaSchemaRef {
+                MetaSchemaRef::Inline(Box::new(MetaSchema::new_with_format("string", $format)))
+            }
+
+            fn as_raw_value(&self) -> Option<&Self::RawValueType> {
+                Some(self)
+            }
+
+            fn raw_element_iter<'a>(
+                &'a self,
+            ) -> Box<dyn Iterator<Item = &'a Self::RawElementValueType> + 'a> {
+                Box::new(self.as_raw_value().into_iter())
+            }
+        }
+
+        impl ParseFromParameter for $ty {
+            fn parse_from_parameter(value: &str) -> ParseResult<Self> {
+                Self::parse(value).map_err(|_| concat!("invalid ", $format).into())
+            }
+        }
+    };
+}
+
+impl_id_type!(
+    /// The id of a repo, as used in URL paths.
+    RepoId,
+    "repo-id",
+    is_valid_segment
+);
+
+impl_id_type!(
+    /// The id of a package, as used in URL paths.
+    PackageId,
+    "package-id",
+    is_valid_segment
+);
+
+impl_id_type!(
+    /// A strings file name such as `sme.toml`, as used in URL paths.
+    LangTag,
+    "lang-tag",
+    is_valid_strings_file
+);
+
+impl_id_type!(
+    /// The file name of an uploaded artifact, as used in URL paths.
+    ArtifactName,
+    "artifact-name",
+    is_valid_segment
+);
+
+impl RepoId {
+    /// Whether this repo is one of the configured, hosted repos.
+    pub fn is_hosted(&self, config: &Config) -> bool {
+        config.repos.iter().any(|x| x == &self.0)
+    }
+}
+
+impl LangTag {
+    /// The language tag without the `.toml` suffix, defaulting to `en`.
+    pub fn tag(&self) -> &str {
+        match self.0.strip_suffix(".toml") {
+            Some("") | None => "en",
+            Some(v) => v,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unsafe_segments() -> Vec<String> {
+        vec![
+            "".to_string(),
+            ".".to_string(),
+            "..".to_string(),
+            ".hidden".to_string(),
+            "a/b".to_string(),
+            "../main".to_string(),
+            "a\\b".to_string(),
+            "..\\main".to_string(),
+            "%2e%2e".to_string(),
+            "sámi".to_string(),
+            "speller sme".to_string(),
+            "a".repeat(MAX_ID_LENGTH + 1),
+        ]
+    }
+
+    #[test]
+    fn accepts_plain_segments() {
+        for value in [
+            "main",
+            "speller-sme",
+            "speller_sme",
+            "sme.1.0",
+            "a..b",
+            "A1",
+        ] {
+            assert!(is_valid_segment(value), "rejected {:?}", value);
+        }
+        assert!(is_valid_segment(&"a".repeat(MAX_ID_LENGTH)));
+    }
+
+    #[test]
+    fn rejects_unsafe_segments() {
+        for value in unsafe_segments() {
+            assert!(!is_valid_segment(&value), "accepted {:?}", value);
+        }
+    }
+
+    #[test]
+    fn accepts_strings_files() {
+        for value in [".toml", "en.toml", "sme.toml", "se-NO.toml", "zh_Hant.toml"] {
+            assert!(is_valid_strings_file(value), "rejected {:?}", value);
+        }
+        let longest = format!("{}.toml", "a".repeat(MAX_ID_LENGTH));
+        assert!(is_valid_strings_file(&longest));
+    }
+
+    #[test]
+    fn rejects_unsafe_strings_files() {
+        let too_long = format!("{}.toml", "a".repeat(MAX_ID_LENGTH + 1));
+        for value in [
+            "",
+            "sme",
+            "..toml",
+            "...toml",
+            "../sme.toml",
+            "a/b.toml",
+            "a\\b.toml",
+            ".hidden.toml",
+            "sme.toml.bak",
+            "sámi.toml",
+            too_long.as_str(),
+        ] {
+            assert!(!is_valid_strings_file(value), "accepted {:?}", value);
+        }
+    }
+
+    #[test]
+    fn parameters_are_validated() {
+        assert_eq!(
+            RepoId::parse_from_parameter("main").unwrap().as_str(),
+            "main"
+        );
+        assert_eq!(
+            LangTag::parse_from_parameter("sme.toml").unwrap().tag(),
+            "sme"
+        );
+        assert_eq!(LangTag::parse_from_parameter(".toml").unwrap().tag(), "en");
+
+        for value in unsafe_segments() {
+            assert!(RepoId::parse_from_parameter(&value).is_err(), "{:?}", value);
+            assert!(
+                PackageId::parse_from_parameter(&value).is_err(),
+                "{:?}",
+                value
+            );
+            assert!(
+                ArtifactName::parse_from_parameter(&value).is_err(),
+                "{:?}",
+                value
+            );
+            assert!(
+                LangTag::parse_from_parameter(&value).is_err(),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn errors_name_the_kind_and_value() {
+        let error = PackageId::parse("../main").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid package-id: `../main`");
+    }
+}
@@ -77,7 +77,7 @@ fn create_payload_windows_exe<'a>(
     if payload.requires_reboot.contains(&RebootSpec::Update) {
         flags |= WindowsExecutableFlag::RequiresRebootOnUpdate as u8;
     }
-    if payload.requires_reboot.contains(&RebootSpec::Install) {
+    if payload.requires_reboot.contains(&RebootSpec::Uninstall) {
         flags |= WindowsExecutableFlag::RequiresRebootOnUninstall as u8;
     }
 
@@ -112,7 +112,7 @@ fn create_payload_macos_pkg<'a>(
     if payload.requires_reboot.contains(&RebootSpec::Update) {
         flags |= MacOSPackageFlag::RequiresRebootOnUpdate as u8;
     }
-    if payload.requires_reboot.contains(&RebootSpec::Install) {
+    if payload.requires_reboot.contains(&RebootSpec::Uninstall) {
         flags |= MacOSPackageFlag::RequiresRebootOnUninstall as u8;
     }
 
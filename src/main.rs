@@ -1,9 +1,26 @@
+mod artifacts;
+mod atom;
+mod cache;
+mod checksums;
+mod decoding;
+mod delta;
+mod deps;
+mod encoding;
+mod feed;
 mod git;
 mod graphql;
+mod ids;
 mod indexing;
 mod openapi;
+mod publish;
+mod release;
+mod search;
+mod signing;
 mod state;
+mod stats;
 mod toml;
+mod validate;
+mod webhooks;
 
 use std::{
     collections::HashMap,
@@ -15,10 +32,12 @@ use std::{
 
 use arc_swap::ArcSwap;
 use async_graphql::{
-    http::{playground_source, GraphQLPlaygroundConfig},
-    EmptyMutation, EmptySubscription, Schema,
+    extensions::apollo_persisted_queries::{ApolloPersistedQueries, LruCacheStorage},
+    http::{parse_query_string, playground_source, GraphQLPlaygroundConfig},
+    Schema,
 };
-use async_graphql_poem::GraphQL;
+use async_graphql_poem::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use chrono::{DateTime, Utc};
 use fbs::FlatBufferBuilder;
 use figment::{
     providers::{Env, Format, Toml as FigmentToml},
@@ -28,7 +47,13 @@ use once_cell::sync::Lazy;
 use pahkat_types::package::{version::SemanticVersion, Version};
 use parking_lot::RwLock;
 use poem::{
-    get, handler, listener::TcpListener, web::Html, EndpointExt, IntoResponse, Result, Route, middleware::Cors,
+    error::BadRequest,
+    get, handler,
+    http::{header, HeaderValue, StatusCode},
+    listener::TcpListener,
+    middleware::Cors,
+    web::{Data, Html},
+    EndpointExt, IntoResponse, Request, Result, Route,
 };
 use poem_openapi::OpenApiService;
 use serde::{Deserialize, Serialize};
@@ -38,65 +63,38 @@ use structopt::StructOpt;
 use uuid::Uuid;
 
 use crate::{
+    encoding::EncodedBody,
     git::GitRepo,
-    graphql::Query,
+    graphql::{Mutation, Query, Subscription},
     state::{init_repo_indexes, set_repo_indexes, REPO_INDEXES},
 };
 
+/// Builds the index served to pahkat-client 0.1.0 from the `divvun-installer`
+/// repo snapshot, so it always matches the served `index.bin`.
 fn generate_010_workaround_index(
     config: &Config,
-    divvun_installer_repo_path: &std::path::Path,
+    divvun_installer: &RepoIndexData,
 ) -> Result<Vec<u8>, std::io::Error> {
-    let dm_path = divvun_installer_repo_path
-        .join("packages")
-        .join("divvun-installer")
-        .join("index.toml");
-    let pahkat_path = divvun_installer_repo_path
-        .join("packages")
-        .join("pahkat-service")
-        .join("index.toml");
-
-    tracing::trace!("Attempting read to string: {:?}", &dm_path);
-    let dm_file = match std::fs::read_to_string(&dm_path) {
-        Ok(v) => v,
-        Err(e) => {
-            tracing::error!("Could not handle path: {:?}", &dm_path);
-            tracing::error!("{}", e);
-            tracing::error!("Continuing.");
-            return Err(e);
-        }
-    };
-    tracing::trace!("Attempting read to string: {:?}", &pahkat_path);
-    let pahkat_file = match std::fs::read_to_string(&pahkat_path) {
-        Ok(v) => v,
-        Err(e) => {
-            tracing::error!("Could not handle path: {:?}", &pahkat_path);
+    let descriptor = |package_id: &str| {
+        let file = divvun_installer
+            .descriptors
+            .get(package_id)
+            .ok_or_else(|| {
+                tracing::error!("No descriptor for {} in snapshot", package_id);
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("missing package {}", package_id),
+                )
+            })?;
+        ::toml::from_str::<pahkat_types::package::Descriptor>(file).map_err(|e| {
+            tracing::error!("Could not parse descriptor for {}", package_id);
             tracing::error!("{}", e);
-            tracing::error!("Continuing.");
-            return Err(e);
-        }
+            std::io::Error::new(std::io::ErrorKind::Other, e)
+        })
     };
 
-    let mut dm_package: pahkat_types::package::Descriptor = match ::toml::from_str(&dm_file) {
-        Ok(v) => v,
-        Err(e) => {
-            tracing::error!("Could not parse: {:?}", &dm_path);
-            tracing::error!("{}", e);
-            tracing::error!("Continuing.");
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
-        }
-    };
-
-    let mut pahkat_package: pahkat_types::package::Descriptor = match ::toml::from_str(&pahkat_file)
-    {
-        Ok(v) => v,
-        Err(e) => {
-            tracing::error!("Could not parse: {:?}", &pahkat_path);
-            tracing::error!("{}", e);
-            tracing::error!("Continuing.");
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, e));
-        }
-    };
+    let mut dm_package = descriptor("divvun-installer")?;
+    let mut pahkat_package = descriptor("pahkat-service")?;
 
     let mut windows_divvun_inst = dm_package
         .release
@@ -184,8 +182,8 @@ fn generate_repo_index(
     tracing::debug!("Attempting to load repo in path: {:?}", &path);
 
     let index_path = path.join("index.toml");
-    let repo_index = std::fs::read_to_string(index_path).unwrap();
-    let repo_index = ::toml::from_str(&repo_index)
+    let index_toml = std::fs::read_to_string(index_path).unwrap();
+    let repo_index = ::toml::from_str(&index_toml)
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
     let packages_path = path.join("packages");
@@ -195,6 +193,16 @@ fn generate_repo_index(
     let strings_path = path.join("strings");
     std::fs::create_dir_all(&strings_path)?;
 
+    let mut strings = HashMap::new();
+    for entry in std::fs::read_dir(&strings_path)?.filter_map(Result::ok) {
+        let path = entry.path();
+        let lang = match path.file_stem().and_then(|x| x.to_str()) {
+            Some(v) if path.extension().map(|x| x == "toml").unwrap_or(false) => v.to_string(),
+            _ => continue,
+        };
+        strings.insert(lang, Arc::from(std::fs::read_to_string(&path)?));
+    }
+
     // Find all package descriptor TOMLs
     let packages = std::fs::read_dir(&*packages_path)?
         .filter_map(Result::ok)
@@ -224,20 +232,75 @@ fn generate_repo_index(
                     return None;
                 }
             };
-            Some(package)
+            let checksums = match checksums::ChecksumFile::read(&x.path()) {
+                Ok(v) => v.checksum,
+                Err(e) => {
+                    tracing::error!("Could not read checksums for: {:?}", &path);
+                    tracing::error!("{}", e);
+                    tracing::error!("Continuing.");
+                    vec![]
+                }
+            };
+            let dir_name = x.file_name().to_string_lossy().to_string();
+            Some((dir_name, package, file, checksums))
         })
-        .collect::<Vec<pahkat_types::package::Package>>();
+        .collect::<Vec<(String, pahkat_types::package::Package, String, Vec<_>)>>();
+
+    let descriptors = packages
+        .iter()
+        .map(|(dir_name, _, file, _)| (dir_name.clone(), Arc::from(file.as_str())))
+        .collect::<HashMap<_, _>>();
+    let checksums = packages
+        .iter()
+        .filter(|(_, _, _, checksums)| !checksums.is_empty())
+        .map(|(_, p, _, checksums)| (p.id().to_string(), Arc::from(checksums.as_slice())))
+        .collect::<HashMap<_, _>>();
+    let packages = packages
+        .into_iter()
+        .map(|(_, p, _, _)| p)
+        .collect::<Vec<_>>();
 
     let mut builder = FlatBufferBuilder::new();
     let index = indexing::build_index(&mut builder, &packages).map_err(|_| {
         std::io::Error::new(std::io::ErrorKind::Other, "failed to generate flatbuffer")
     })?;
 
+    decoding::verify_index(&packages, index).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("generated flatbuffer failed verification: {:#}", e),
+        )
+    })?;
+
+    let package_ids = packages
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.id().to_string(), i))
+        .collect();
+
+    let last_modified = git::head_commit_time(path)?;
+
+    let signer = state::SIGNER.get().and_then(|x| x.as_ref());
+    let index_toml = match signer {
+        Some(signer) => signing::with_public_key(&index_toml, &signer.public_key()).into_owned(),
+        None => index_toml,
+    };
+    let index_bin_signature = signer.map(|x| x.sign(index));
+    let index_toml_signature = signer.map(|x| x.sign(index_toml.as_bytes()));
+
     Ok(RepoIndexData {
         head_ref,
+        last_modified,
+        package_ids,
         packages: Arc::from(packages),
         repo_index: Arc::new(repo_index),
-        package_index: Arc::from(index.to_vec()),
+        package_index: EncodedBody::new(index.to_vec())?,
+        index_toml: Arc::from(index_toml),
+        descriptors,
+        checksums,
+        strings,
+        index_bin_signature,
+        index_toml_signature,
     })
 }
 
@@ -257,8 +320,15 @@ async fn refresh_indexes(
         if s.head_ref != head_ref {
             tracing::info!("Updating index for {}", repo_id);
             let repo_index_data =
-                generate_repo_index(head_ref.clone(), &tmpdir.path().join(repo_id)).unwrap();
-            set_repo_indexes(state, repo_index_data);
+                match generate_repo_index(head_ref.clone(), &tmpdir.path().join(repo_id)) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        tracing::error!(error = ?e, "Rejected new index for {}", repo_id);
+                        tracing::error!("Continuing.");
+                        continue;
+                    }
+                };
+            set_repo_indexes(repo_id, state, repo_index_data);
             tracing::info!("Finished updating index for {}", repo_id);
         }
     }
@@ -286,17 +356,151 @@ async fn refresh_indexes_forever(
 #[derive(Debug)]
 struct RepoIndexData {
     head_ref: Arc<str>,
+    /// Commit time of `head_ref`
+    last_modified: DateTime<Utc>,
     packages: Arc<[pahkat_types::package::Package]>,
+    /// Position of each package in `packages`, by id
+    package_ids: HashMap<String, usize>,
     repo_index: Arc<pahkat_types::repo::Index>,
-    package_index: Arc<[u8]>,
+    /// The flatbuffer index, with precompressed encodings
+    package_index: EncodedBody,
+    /// Raw `index.toml` of the repo, with a `[signing]` table added when
+    /// signing is configured
+    index_toml: Arc<str>,
+    /// Raw `index.toml` of each package, by directory name under `packages/`
+    descriptors: HashMap<String, Arc<str>>,
+    /// Payload checksums of each package, by id
+    checksums: HashMap<String, Arc<[checksums::PayloadChecksum]>>,
+    /// Raw strings TOML, by language tag
+    strings: HashMap<String, Arc<str>>,
+    /// Signature of `package_index` (uncompressed), if signing is configured
+    index_bin_signature: Option<signing::DetachedSignature>,
+    /// Signature of `index_toml`, if signing is configured
+    index_toml_signature: Option<signing::DetachedSignature>,
+}
+
+impl RepoIndexData {
+    fn package(&self, package_id: &str) -> Option<&pahkat_types::package::Package> {
+        self.package_ids.get(package_id).map(|&i| &self.packages[i])
+    }
+
+    fn checksum(
+        &self,
+        package_id: &str,
+        release: &pahkat_types::package::Release,
+        target: &pahkat_types::payload::Target,
+    ) -> Option<checksums::PayloadChecksum> {
+        checksums::find(
+            self.checksums.get(package_id)?,
+            &release.version.to_string(),
+            release.channel.as_deref(),
+            target,
+        )
+    }
+}
+
+#[cfg(test)]
+impl RepoIndexData {
+    /// An unsigned index of `packages` at `head_ref`, as served for `repo_id`.
+    pub(crate) fn for_tests(
+        repo_id: &str,
+        head_ref: &str,
+        packages: Vec<pahkat_types::package::Package>,
+    ) -> Self {
+        let index_toml = format!(
+            "[repository]\nurl = \"https://pahkat.example/{}\"\nchannels = []\n\n\
+             [name]\nen = \"{}\"\n\n[description]\nen = \"{}\"\n",
+            repo_id, repo_id, repo_id
+        );
+
+        let mut builder = FlatBufferBuilder::new();
+        let index = indexing::build_index(&mut builder, &packages).unwrap();
+
+        RepoIndexData {
+            head_ref: Arc::from(head_ref),
+            last_modified: Utc::now(),
+            package_ids: packages
+                .iter()
+                .enumerate()
+                .map(|(i, p)| (p.id().to_string(), i))
+                .collect(),
+            package_index: EncodedBody::new(index.to_vec()).unwrap(),
+            packages: Arc::from(packages),
+            repo_index: Arc::new(::toml::from_str(&index_toml).unwrap()),
+            index_toml: Arc::from(index_toml),
+            descriptors: HashMap::new(),
+            checksums: HashMap::new(),
+            strings: HashMap::new(),
+            index_bin_signature: None,
+            index_toml_signature: None,
+        }
+    }
 }
 
 type RepoIndex = ArcSwap<RepoIndexData>;
 type RepoIndexes = Arc<HashMap<String, RepoIndex>>;
 
+type AppSchema = Schema<Query, Mutation, Subscription>;
+
+/// Number of persisted query hashes remembered by the server.
+const PERSISTED_QUERY_CACHE_SIZE: usize = 1024;
+
+fn with_bearer_token(req: &Request, gql_req: async_graphql::Request) -> async_graphql::Request {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+    match token {
+        Some(token) => gql_req.data(graphql::BearerToken(token.to_string())),
+        None => gql_req,
+    }
+}
+
+/// Serves the playground, or executes the query when one is given in the
+/// query string (as persisted query clients do).
+///
+/// Mutations and subscriptions are refused with 405, since GET requests can
+/// be triggered cross-site and are cached by intermediaries.
 #[handler]
-async fn graphql_playground() -> impl IntoResponse {
-    Html(playground_source(GraphQLPlaygroundConfig::new("/graphql")))
+async fn graphql_get(schema: Data<&AppSchema>, req: &Request) -> Result<poem::Response> {
+    let query = req.uri().query().unwrap_or_default();
+    if query.is_empty() {
+        return Ok(Html(playground_source(
+            GraphQLPlaygroundConfig::new("/graphql").subscription_endpoint("/graphql/ws"),
+        ))
+        .into_response());
+    }
+
+    let gql_req = parse_query_string(query)
+        .map_err(BadRequest)?
+        .data(graphql::GetRequest);
+    let response = schema.execute(with_bearer_token(req, gql_req)).await;
+    let rejected = response
+        .errors
+        .iter()
+        .any(|e| e.message == graphql::QUERY_ONLY_ERROR);
+
+    let mut response = GraphQLResponse::from(response).into_response();
+    if rejected {
+        response.set_status(StatusCode::METHOD_NOT_ALLOWED);
+        response
+            .headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static("POST"));
+    }
+    Ok(response)
+}
+
+#[handler]
+async fn graphql_handler(
+    schema: Data<&AppSchema>,
+    req: &Request,
+    gql_req: GraphQLRequest,
+) -> GraphQLResponse {
+    schema
+        .execute(with_bearer_token(req, gql_req.0))
+        .await
+        .into()
 }
 
 async fn run(config: Config) -> Result<(), std::io::Error> {
@@ -310,8 +514,14 @@ async fn run(config: Config) -> Result<(), std::io::Error> {
         REPO_INDEXES.get().unwrap(),
     ));
 
-    let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+    let schema = Schema::build(Query, Mutation, Subscription)
         .data(config.clone())
+        .limit_depth(config.graphql_max_depth)
+        .limit_complexity(config.graphql_max_complexity)
+        .extension(ApolloPersistedQueries::new(LruCacheStorage::new(
+            PERSISTED_QUERY_CACHE_SIZE,
+        )))
+        .extension(graphql::QueryOnlyOverGet)
         .finish();
 
     let api_service = OpenApiService::new(
@@ -324,10 +534,9 @@ async fn run(config: Config) -> Result<(), std::io::Error> {
     let app = Route::new()
         .nest("/", api_service)
         .nest("/playground", ui)
-        .at(
-            "/graphql",
-            get(graphql_playground).post(GraphQL::new(schema)),
-        )
+        .at("/graphql", get(graphql_get).post(graphql_handler))
+        .at("/graphql/ws", get(GraphQLSubscription::new(schema.clone())))
+        .data(schema)
         .data(config.clone())
         .data(openapi::ServerToken(config.api_token.clone()))
         .with(Cors::default());
@@ -367,16 +576,147 @@ pub struct Config {
     /// Skip git repo clean-up (useful for development)
     #[serde(default)]
     skip_repo_cleanup: bool,
+
+    /// Other URLs dependency keys may use to refer to a hosted repo, by repo
+    /// id, for when the URL in its index differs between deployments
+    #[serde(default)]
+    repo_aliases: HashMap<String, Vec<String>>,
+
+    /// What to do when an update depends on packages no hosted repo provides
+    #[serde(default)]
+    dependency_policy: DependencyPolicy,
+
+    /// File to persist download counts to (kept in memory only if unset)
+    #[serde(default)]
+    stats_path: Option<PathBuf>,
+
+    /// Endpoints notified after a package is created or updated
+    #[serde(default)]
+    webhooks: Vec<webhooks::WebhookConfig>,
+
+    /// File that webhook deliveries are appended to once all retries failed
+    #[serde(default)]
+    webhook_dead_letter_path: Option<PathBuf>,
+
+    /// `Cache-Control` sent with index, descriptor and strings responses
+    /// (default: no-cache)
+    #[serde(default = "default_cache_control")]
+    cache_control: String,
+
+    /// Check given payload checksums against the artifact before publishing
+    #[serde(default)]
+    verify_payloads: bool,
+
+    /// Largest artifact, in bytes, accepted as an upload or fetched for
+    /// payload verification (default: 2 GiB)
+    #[serde(default = "default_max_artifact_size")]
+    max_artifact_size: u64,
+
+    /// Local directory holding artifacts by file name, checked before
+    /// downloading a payload URL for verification. Uploaded artifacts are
+    /// stored here unless `artifact_s3` is set.
+    #[serde(default)]
+    artifact_root: Option<PathBuf>,
+
+    /// Public URL prefix artifacts in `artifact_root` are served from
+    /// (default: `<url>/artifacts`)
+    #[serde(default)]
+    artifact_url: Option<String>,
+
+    /// S3-compatible bucket to store uploaded artifacts in
+    #[serde(default)]
+    artifact_s3: Option<artifacts::S3Config>,
+
+    /// Secret ed25519 key used to sign indexes (see `generate-key`)
+    #[serde(default)]
+    signing_key_path: Option<PathBuf>,
+
+    /// Base64 public keys that used to sign indexes, still published so
+    /// clients can verify older signatures during a key rotation
+    #[serde(default)]
+    retired_public_keys: Vec<String>,
+
+    /// Deepest nesting a GraphQL query may have (default: 12)
+    #[serde(default = "default_graphql_max_depth")]
+    graphql_max_depth: usize,
+
+    /// Highest complexity a GraphQL query may have (default: 1000)
+    #[serde(default = "default_graphql_max_complexity")]
+    graphql_max_complexity: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyPolicy {
+    /// Refuse the update
+    #[default]
+    Reject,
+
+    /// Log a warning and accept the update
+    Warn,
 }
 
 fn default_branch_name() -> String {
     "main".to_string()
 }
 
+fn default_cache_control() -> String {
+    "no-cache".to_string()
+}
+
+fn default_max_artifact_size() -> u64 {
+    2 * 1024 * 1024 * 1024
+}
+
+fn default_graphql_max_depth() -> usize {
+    12
+}
+
+fn default_graphql_max_complexity() -> usize {
+    1000
+}
+
+#[cfg(test)]
+impl Config {
+    /// A config hosting `repos` from `git_path`, with defaults for everything
+    /// optional.
+    pub(crate) fn for_tests(git_path: &path::Path, repos: &[&str]) -> Self {
+        serde_json::from_value(serde_json::json!({
+            "api_token": "token",
+            "git_path": git_path,
+            "repos": repos,
+            "url": "https://pahkat.example",
+            "host": "127.0.0.1",
+            "port": 0,
+            "index_interval": 60,
+        }))
+        .unwrap()
+    }
+}
+
 #[derive(StructOpt)]
 struct Args {
     #[structopt(short, long)]
     config_path: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Lint all hosted repos offline and print a JSON report
+    ///
+    /// Exits with a non-zero status if any issue is found.
+    Validate,
+
+    /// Generate an ed25519 index signing key
+    ///
+    /// Writes the secret key to `path` and prints the public key as JSON.
+    GenerateKey {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
 }
 
 #[tokio::main]
@@ -386,6 +726,12 @@ async fn main() -> anyhow::Result<()> {
 
     let args = Args::from_args();
 
+    if let Some(Command::GenerateKey { path }) = &args.command {
+        let public_key = signing::generate_key(path)?;
+        println!("{}", serde_json::to_string_pretty(&public_key)?);
+        return Ok(());
+    }
+
     let mut figment = Figment::new();
     if let Some(config_path) = args.config_path {
         figment = figment.merge(FigmentToml::file(config_path));
@@ -399,5 +745,16 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
-    Ok(run(config).await?)
+    match args.command {
+        Some(Command::Validate) => {
+            let report = validate::validate(&config);
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            match report.exit_code() {
+                0 => Ok(()),
+                code => std::process::exit(code),
+            }
+        }
+        Some(Command::GenerateKey { .. }) => unreachable!("handled before loading config"),
+        None => Ok(run(config).await?),
+    }
 }
@@ -1,28 +1,58 @@
 use crate::{
+    artifacts::{self, ByteRange, UploadedArtifact},
+    atom::Atom,
+    cache::{self, Validators},
+    checksums::PayloadChecksum,
+    delta::IndexDelta,
+    deps::{self, ResolvedPackage, ReverseDependency},
+    encoding::ContentEncoding,
+    feed::{self, FeedEntry, FeedFilter, JsonFeed},
     generate_010_workaround_index, generate_empty_index,
-    state::{ServerStatus, GIT_REPO, REPO_INDEXES, SERVER_STATUS},
+    ids::{ArtifactName, LangTag, PackageId, RepoId},
+    publish,
+    release::{self, ReleaseQuery},
+    search::PackageFilter,
+    signing::{self, DetachedSignature, PublicKeyInfo},
+    state::{
+        index_generation, ServerStatus, ARTIFACT_STORE, DOWNLOAD_STATS, REPO_INDEXES,
+        SERVER_STATUS, SIGNER,
+    },
+    stats::{DownloadEvent, PackageDownloads},
     toml::Toml,
-    Config,
+    Config, RepoIndexData,
 };
-use chrono::{DateTime, Utc};
-use once_cell::sync::{Lazy, OnceCell};
-use pahkat_repomgr::package;
-use pahkat_types::{package::Descriptor, package_key::PackageKeyParams};
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use pahkat_types::{
+    package::{Descriptor, Release},
+    package_key::PackageKeyParams,
+    payload::{Payload, Target},
+    repo::Index,
+};
+use parking_lot::Mutex;
 use poem::{
-    error::{BadRequest, Conflict, InternalServerError, NotFoundError},
-    http::StatusCode,
+    error::{BadRequest, InternalServerError, NotFoundError, UnprocessableEntity},
+    http::{header, StatusCode},
     web::Data,
-    Request, Result,
+    Body, Request, Result,
 };
 use poem_openapi::{
     auth::Bearer,
-    param::{Header, Path},
+    param::{Header, Path, Query},
     payload::{Binary, Json, Response},
     Object, OpenApi, SecurityScheme,
 };
-use std::{borrow::Cow, fmt::Display, path, sync::Arc};
+use serde::Deserialize;
+use std::{fmt::Display, io::SeekFrom, sync::Arc};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Names the commit an index response was generated from, so the matching
+/// signature can be requested with `?ref=`.
+const INDEX_REF_HEADER: &str = "X-Pahkat-Index-Ref";
 
-static DIVVUN_INST_REPO_INDEX: OnceCell<Arc<[u8]>> = OnceCell::new();
+/// The workaround index for old clients, with the `head_ref` it was built from.
+static DIVVUN_INST_REPO_INDEX: Lazy<Mutex<Option<(Arc<str>, Bytes)>>> = Lazy::new(Default::default);
 
 pub struct Api;
 
@@ -52,6 +82,10 @@ pub struct UpdatePackageMetadataRequest {
     pub license: Option<String>,
     pub license_url: Option<String>,
     pub target: pahkat_types::payload::Target,
+    /// Hex encoded SHA-256 of the payload
+    pub sha256: Option<String>,
+    /// Hex encoded BLAKE3 of the payload
+    pub blake3: Option<String>,
 }
 
 #[derive(Object, Debug, Clone)]
@@ -70,6 +104,62 @@ pub struct CreatePackageMetadataResponse {
     timestamp: DateTime<Utc>,
 }
 
+/// Query parameters for `download` and `latest`.
+///
+/// `arch` falls back to compatible architectures (e.g. arm64 to x86_64) when
+/// there is no native build. `version` pins an exact release.
+#[derive(Debug, Clone, Deserialize)]
+struct ReleaseParams {
+    platform: Option<String>,
+    arch: Option<String>,
+    channel: Option<String>,
+    version: Option<String>,
+}
+
+#[derive(Object, Debug, Clone)]
+struct LatestReleaseResponse {
+    repo_id: String,
+    package_id: String,
+    version: String,
+    release: Release,
+    target: Target,
+    payload: Payload,
+    checksum: Option<PayloadChecksum>,
+}
+
+/// A package descriptor with the payload checksums recorded for its release
+/// targets.
+#[derive(Object, Debug, Clone)]
+struct PackageDescriptor {
+    #[oai(flatten)]
+    descriptor: Descriptor,
+    checksums: Vec<PayloadChecksum>,
+}
+
+impl PackageDescriptor {
+    fn new(state: &RepoIndexData, descriptor: &Descriptor) -> Self {
+        Self {
+            checksums: state
+                .checksums
+                .get(descriptor.package.id.as_str())
+                .map(|x| x.to_vec())
+                .unwrap_or_default(),
+            descriptor: descriptor.clone(),
+        }
+    }
+}
+
+#[derive(Object, Debug, Clone)]
+struct PackageList {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    packages: Vec<PackageDescriptor>,
+}
+
+pub(crate) const DEFAULT_PAGE_LIMIT: usize = 50;
+pub(crate) const MAX_PAGE_LIMIT: usize = 500;
+
 impl Display for UpdatePackageMetadataRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
@@ -99,50 +189,171 @@ async fn check_bearer_token(req: &Request, bearer: Bearer) -> Option<()> {
 }
 
 #[derive(Debug, thiserror::Error)]
-enum PackageUpdateError {
-    #[error("Invalid version provided")]
-    VersionError(#[from] pahkat_types::package::version::Error),
+#[error("Missing query parameter for `platform`")]
+struct MissingQueryParamPlatformError;
 
-    #[error("Repo error: {0}")]
-    RepoError(#[source] package::update::Error),
+/// The current index of a hosted repo.
+fn snapshot(repo_id: &str) -> Result<Arc<RepoIndexData>> {
+    match REPO_INDEXES.get().unwrap().get(repo_id) {
+        Some(v) => Ok(v.load_full()),
+        None => Err(NotFoundError.into()),
+    }
 }
 
-#[derive(Debug, thiserror::Error)]
-#[error("Missing query parameter for `platform`")]
-struct MissingQueryParamPlatformError;
+/// The index generation a signature is requested for: the one built from
+/// `index_ref` when given (while it is still retained), else the current one.
+fn signed_snapshot(repo_id: &str, index_ref: Option<&str>) -> Result<Arc<RepoIndexData>> {
+    match index_ref {
+        Some(index_ref) => index_generation(repo_id, index_ref).ok_or_else(|| NotFoundError.into()),
+        None => snapshot(repo_id),
+    }
+}
 
-#[derive(Debug, thiserror::Error)]
-#[error("Package with identifier `{0}` already exists.")]
-struct PackageExistsError(String);
+/// A descriptor from the current index of a hosted repo.
+fn snapshot_descriptor(repo_id: &str, package_id: &str) -> Result<Descriptor> {
+    match snapshot(repo_id)?.package(package_id) {
+        Some(pahkat_types::package::Package::Concrete(v)) => Ok(v.clone()),
+        _ => Err(NotFoundError.into()),
+    }
+}
 
-fn modify_repo_metadata(
-    path: &path::Path,
+fn cached_toml(
+    config: &Config,
+    req: &Request,
+    state: &RepoIndexData,
+    output: &str,
+) -> Result<Response<Toml<String>>> {
+    let validators = Validators::new(output.as_bytes(), Some(state.last_modified));
+
+    if validators.is_not_modified(req) {
+        return Ok(cache::not_modified(
+            config,
+            &validators,
+            Toml(String::new()),
+        ));
+    }
+
+    Ok(cache::cached(config, &validators, Toml(output.to_string())))
+}
+
+/// Streams a file from `artifact_root`, honouring conditional and `Range`
+/// requests so interrupted downloads can be resumed.
+async fn serve_artifact(
+    config: &Config,
+    req: &Request,
+    path: &std::path::Path,
+    file_name: &str,
+) -> Result<Response<Binary<Body>>> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(InternalServerError)?;
+    let metadata = file.metadata().await.map_err(InternalServerError)?;
+    let size = metadata.len();
+    let modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+
+    // Artifacts are never replaced, so size and modification time are enough
+    // to tell them apart without hashing the file.
+    let validators = Validators::with_etag(
+        format!(
+            "\"{:x}-{:x}\"",
+            size,
+            modified.map(|x| x.timestamp()).unwrap_or_default()
+        ),
+        modified,
+    );
+
+    let response = if validators.is_not_modified(req) {
+        cache::not_modified(config, &validators, Binary(Body::empty()))
+    } else {
+        let range = match req
+            .headers()
+            .get(header::RANGE)
+            .and_then(|x| x.to_str().ok())
+        {
+            Some(value) if validators.is_range_current(req) => ByteRange::parse(value, size),
+            _ => Ok(None),
+        };
+
+        match range {
+            Ok(None) => cache::cached(config, &validators, Binary(Body::from_async_read(file)))
+                .header(header::CONTENT_LENGTH, size),
+            Ok(Some(range)) => {
+                file.seek(SeekFrom::Start(range.start))
+                    .await
+                    .map_err(InternalServerError)?;
+                cache::cached(
+                    config,
+                    &validators,
+                    Binary(Body::from_async_read(file.take(range.len()))),
+                )
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, range.end, size),
+                )
+                .header(header::CONTENT_LENGTH, range.len())
+            }
+            Err(_) => Response::new(Binary(Body::empty()))
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size)),
+        }
+    };
+
+    Ok(response.header(header::ACCEPT_RANGES, "bytes").header(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", file_name),
+    ))
+}
+
+/// Whether the request resumes a download part way through, so it should not
+/// be counted again.
+fn is_resumed_download(req: &Request) -> bool {
+    req.headers()
+        .get(header::RANGE)
+        .and_then(|x| x.to_str().ok())
+        .map(artifacts::resumes_download)
+        .unwrap_or(false)
+}
+
+async fn feed_entries(
+    config: &Config,
+    repo_id: &str,
+    filter: &FeedFilter<'_>,
+) -> Result<Vec<FeedEntry>> {
+    let state = snapshot(repo_id)?;
+    let commits = feed::update_commits(repo_id, &state.head_ref, filter)
+        .await
+        .map_err(InternalServerError)?;
+
+    Ok(feed::entries(config, repo_id, &state, &commits, filter))
+}
+
+/// Finds the release `download` and `latest` serve for a package.
+fn find_latest_release(
+    repo_id: &str,
     package_id: &str,
-    release: &UpdatePackageMetadataRequest,
-) -> Result<(), PackageUpdateError> {
-    let version: pahkat_types::package::Version = match release.version.parse() {
-        Ok(v) => v,
-        Err(e) => return Err(PackageUpdateError::VersionError(e)),
+    params: &ReleaseParams,
+) -> Result<(Release, Target)> {
+    let platform = match params.platform.as_deref() {
+        Some(v) => v,
+        None => {
+            return Err(BadRequest(MissingQueryParamPlatformError));
+        }
     };
 
-    let inner_req = package::update::Request::builder()
-        .repo_path(path.into())
-        .id(package_id.into())
-        .name(release.name.as_ref().map(|x| Cow::Borrowed(&*x)))
-        .description(release.description.as_ref().map(|x| Cow::Borrowed(&*x)))
-        .version(Cow::Owned(version))
-        .channel(release.channel.as_ref().map(|x| Cow::Borrowed(&**x)))
-        .target(Cow::Borrowed(&release.target))
-        .url(None)
-        .build();
-
-    tracing::info!("Updating package...");
-    match package::update::update(inner_req) {
-        Ok(_) => {}
-        Err(e) => return Err(PackageUpdateError::RepoError(e)),
+    let descriptor = snapshot_descriptor(repo_id, package_id)?;
+
+    let query = ReleaseQuery {
+        platform,
+        arch: params.arch.as_deref(),
+        channel: params.channel.as_deref(),
+        version: params.version.as_deref(),
     };
 
-    Ok(())
+    match release::select_release(&descriptor, &query) {
+        Some((release, target)) => Ok((release.clone(), target.clone())),
+        None => Err(NotFoundError.into()),
+    }
 }
 
 #[OpenApi]
@@ -154,57 +365,73 @@ impl Api {
         Ok(Json(status.as_ref().clone()))
     }
 
+    /// List packages
+    ///
+    /// `query` is matched case-insensitively against package ids and all
+    /// localized names and descriptions.
+    #[oai(path = "/:repo_id/packages", method = "get")]
+    #[allow(clippy::too_many_arguments)]
+    async fn list_packages(
+        &self,
+        repo_id: Path<RepoId>,
+        tag: Query<Option<String>>,
+        platform: Query<Option<String>>,
+        channel: Query<Option<String>>,
+        query: Query<Option<String>>,
+        #[oai(default)] offset: Query<usize>,
+        limit: Query<Option<usize>>,
+    ) -> Result<Json<PackageList>> {
+        let state = match REPO_INDEXES.get().unwrap().get(repo_id.as_str()) {
+            Some(v) => v.load(),
+            None => return Err(NotFoundError.into()),
+        };
+
+        let filter = PackageFilter {
+            tag: tag.0,
+            platform: platform.0,
+            channel: channel.0,
+            query: query.0,
+        };
+        let limit = limit.0.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+
+        let matching = state
+            .packages
+            .iter()
+            .filter(|p| filter.matches(p))
+            .filter_map(|p| match p {
+                pahkat_types::package::Package::Concrete(v) => Some(v),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        Ok(Json(PackageList {
+            total: matching.len(),
+            offset: offset.0,
+            limit,
+            packages: matching
+                .into_iter()
+                .skip(offset.0)
+                .take(limit)
+                .map(|x| PackageDescriptor::new(&state, x))
+                .collect(),
+        }))
+    }
+
     /// Create package metadata
     #[oai(path = "/:repo_id/packages/:package_id", method = "post")]
     async fn create_package_metadata(
         &self,
         _auth: BearerTokenAuth,
         config: Data<&Config>,
-        repo_id: Path<String>,
-        package_id: Path<String>,
+        repo_id: Path<RepoId>,
+        package_id: Path<PackageId>,
         data: Json<CreatePackageMetadataRequest>,
     ) -> Result<Json<CreatePackageMetadataResponse>> {
-        if !config.repos.contains(&repo_id) {
-            return Err(NotFoundError.into());
-        }
-
-        let mut guard = GIT_REPO.get().unwrap().write();
-
-        if guard
-            .path
-            .join(&repo_id.0)
-            .join("packages")
-            .join(&package_id.0)
-            .join("index.toml")
-            .exists()
-        {
-            return Err(Conflict(PackageExistsError(package_id.0.clone())));
-        }
-
-        guard.cleanup(&config).map_err(|e| InternalServerError(e))?;
-
-        package::init::init(
-            package::init::Request::builder()
-                .repo_path(guard.path.join(&repo_id.0).into())
-                .id(Cow::Borrowed(&package_id.0))
-                .name(Cow::Borrowed(&data.0.name))
-                .description(Cow::Borrowed(&data.0.description))
-                .tags(Cow::Borrowed(&data.0.tags))
-                .build(),
-        )
-        .map_err(BadRequest)?;
-
-        guard
-            .add_package_to_index_tree(&repo_id.0, &package_id.0)
-            .map_err(|e| InternalServerError(e))?;
-        guard
-            .commit_create(&repo_id.0, &package_id.0)
-            .map_err(|e| InternalServerError(e))?;
-        guard.push(&config).map_err(|e| InternalServerError(e))?;
+        publish::create_package(&config, repo_id.as_str(), package_id.as_str(), &data.0)?;
 
         Ok(Json(CreatePackageMetadataResponse {
-            repo_id: repo_id.0,
-            package_id: package_id.0,
+            repo_id: repo_id.to_string(),
+            package_id: package_id.to_string(),
             success: true,
             error: None,
             timestamp: Utc::now(),
@@ -217,44 +444,144 @@ impl Api {
         &self,
         _auth: BearerTokenAuth,
         config: Data<&Config>,
-        repo_id: Path<String>,
-        package_id: Path<String>,
+        repo_id: Path<RepoId>,
+        package_id: Path<PackageId>,
         data: Json<UpdatePackageMetadataRequest>,
     ) -> Result<Json<UpdatePackageMetadataResponse>> {
-        if !config.repos.contains(&repo_id) {
+        publish::update_package(&config, repo_id.as_str(), package_id.as_str(), &data.0).await?;
+
+        Ok(Json(UpdatePackageMetadataResponse {
+            repo_id: repo_id.to_string(),
+            package_id: package_id.to_string(),
+            success: true,
+            error: None,
+            timestamp: Utc::now(),
+        }))
+    }
+
+    /// Upload artifact
+    ///
+    /// Streams the request body into the artifact store and returns its URL,
+    /// size and checksums for use in a following package update. Existing
+    /// artifacts are never replaced, and artifacts larger than
+    /// `max_artifact_size` are refused with 413.
+    #[oai(path = "/artifacts/:filename", method = "put")]
+    async fn upload_artifact(
+        &self,
+        _auth: BearerTokenAuth,
+        config: Data<&Config>,
+        filename: Path<ArtifactName>,
+        req: &Request,
+        body: Binary<poem::Body>,
+    ) -> Result<Response<Json<UploadedArtifact>>> {
+        let store = ARTIFACT_STORE
+            .get()
+            .and_then(|x| x.as_deref())
+            .ok_or(NotFoundError)?;
+        let content_length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse().ok());
+
+        let artifact = artifacts::upload(
+            store,
+            filename.as_str(),
+            content_length,
+            body.0,
+            config.max_artifact_size,
+        )
+        .await?;
+        Ok(Response::new(Json(artifact)).status(StatusCode::CREATED))
+    }
+
+    /// Download artifact
+    ///
+    /// Serves an artifact stored in `artifact_root`, with support for `Range`
+    /// requests.
+    #[oai(path = "/artifacts/:filename", method = "get")]
+    async fn artifact(
+        &self,
+        config: Data<&Config>,
+        filename: Path<ArtifactName>,
+        req: &Request,
+    ) -> Result<Response<Binary<Body>>> {
+        let path = artifacts::local_path(&config, filename.as_str()).ok_or(NotFoundError)?;
+        serve_artifact(&config, req, &path, filename.as_str()).await
+    }
+
+    /// List reverse dependencies
+    ///
+    /// Every release target in any hosted repo that depends on this package.
+    #[oai(
+        path = "/:repo_id/packages/:package_id/reverse-dependencies",
+        method = "get"
+    )]
+    async fn reverse_dependencies(
+        &self,
+        config: Data<&Config>,
+        repo_id: Path<RepoId>,
+        package_id: Path<PackageId>,
+    ) -> Result<Json<Vec<ReverseDependency>>> {
+        if !repo_id.is_hosted(&config) {
             return Err(NotFoundError.into());
         }
 
-        let mut guard = GIT_REPO.get().unwrap().write();
-        let repo_path = guard.path.join(&repo_id.0);
+        deps::with_hosted_repos(&config, |repos| {
+            let exists = repos
+                .iter()
+                .find(|repo| repo.repo_id == repo_id.as_str())
+                .and_then(|repo| repo.package(package_id.as_str()))
+                .is_some();
+            if !exists {
+                return Err(NotFoundError.into());
+            }
 
-        if !repo_path
-            .join("packages")
-            .join(&package_id.0)
-            .join("index.toml")
-            .exists()
-        {
+            Ok(Json(deps::reverse_dependencies(
+                repos,
+                repo_id.as_str(),
+                package_id.as_str(),
+            )))
+        })
+    }
+
+    /// Resolve install set
+    ///
+    /// The releases to install for a package and all of its dependencies,
+    /// dependencies first.
+    #[oai(path = "/:repo_id/packages/:package_id/resolve", method = "get")]
+    async fn resolve(
+        &self,
+        config: Data<&Config>,
+        repo_id: Path<RepoId>,
+        package_id: Path<PackageId>,
+        params: poem::web::Query<PackageKeyParams>,
+    ) -> Result<Json<Vec<ResolvedPackage>>> {
+        if !repo_id.is_hosted(&config) {
             return Err(NotFoundError.into());
         }
 
-        guard.cleanup(&config).map_err(|e| InternalServerError(e))?;
-        modify_repo_metadata(&repo_path, &package_id.0, &data.0)
-            .map_err(|e| InternalServerError(e))?;
-        guard
-            .add_package_to_index_tree(&repo_id.0, &package_id.0)
-            .map_err(|e| InternalServerError(e))?;
-        guard
-            .commit_update(&repo_id.0, &package_id.0, &data.0)
-            .map_err(|e| InternalServerError(e))?;
-        guard.push(&config).map_err(|e| InternalServerError(e))?;
+        let platform = match params.0.platform.as_deref() {
+            Some(v) => v,
+            None => {
+                return Err(BadRequest(MissingQueryParamPlatformError));
+            }
+        };
 
-        Ok(Json(UpdatePackageMetadataResponse {
-            repo_id: repo_id.0.to_string(),
-            package_id: package_id.0.to_string(),
-            success: true,
-            error: None,
-            timestamp: Utc::now(),
-        }))
+        let query = ReleaseQuery {
+            platform,
+            arch: params.0.arch.as_deref(),
+            channel: params.0.channel.as_deref(),
+            version: None,
+        };
+
+        match deps::with_hosted_repos(&config, |repos| {
+            deps::resolve(repos, repo_id.as_str(), package_id.as_str(), query)
+        }) {
+            Ok(Some(resolved)) => Ok(Json(resolved)),
+            Ok(None) => Err(NotFoundError.into()),
+            Err(e) => Err(UnprocessableEntity(e)),
+        }
     }
 
     #[oai(
@@ -267,18 +594,7 @@ impl Api {
     ) -> Result<Response<Binary<String>>> {
         let platform = "windows";
 
-        let guard = GIT_REPO.get().unwrap().read();
-
-        let index = std::fs::read_to_string(
-            guard
-                .path
-                .join("divvun-installer")
-                .join("packages")
-                .join("pahkat-service")
-                .join("index.toml"),
-        )
-        .map_err(InternalServerError)?;
-        let descriptor: Descriptor = ::toml::from_str(&index).map_err(InternalServerError)?;
+        let descriptor = snapshot_descriptor("divvun-installer", "pahkat-service")?;
 
         for release in descriptor.release {
             if release.channel.as_deref().unwrap_or("stable") != "stable" {
@@ -307,18 +623,7 @@ impl Api {
     ) -> Result<Response<Binary<String>>> {
         let platform = "windows";
 
-        let guard = GIT_REPO.get().unwrap().read();
-
-        let index = std::fs::read_to_string(
-            guard
-                .path
-                .join("divvun-installer")
-                .join("packages")
-                .join("divvun-installer")
-                .join("index.toml"),
-        )
-        .map_err(InternalServerError)?;
-        let descriptor: Descriptor = ::toml::from_str(&index).map_err(InternalServerError)?;
+        let descriptor = snapshot_descriptor("divvun-installer", "divvun-installer")?;
 
         for release in descriptor.release {
             if release.channel.as_deref().unwrap_or("stable") != "stable" {
@@ -337,56 +642,160 @@ impl Api {
         Err(NotFoundError.into())
     }
 
+    /// Get latest release
+    ///
+    /// The highest version release with a target for the given platform,
+    /// arch and channel. This is the release `download` serves.
+    #[oai(path = "/:repo_id/packages/:package_id/latest", method = "get")]
+    async fn latest_release(
+        &self,
+        config: Data<&Config>,
+        repo_id: Path<RepoId>,
+        package_id: Path<PackageId>,
+        params: poem::web::Query<ReleaseParams>,
+    ) -> Result<Json<LatestReleaseResponse>> {
+        if !repo_id.is_hosted(&config) {
+            return Err(NotFoundError.into());
+        }
+
+        let (release, target) =
+            find_latest_release(repo_id.as_str(), package_id.as_str(), &params.0)?;
+        let checksum = snapshot(repo_id.as_str())?.checksum(package_id.as_str(), &release, &target);
+
+        Ok(Json(LatestReleaseResponse {
+            repo_id: repo_id.to_string(),
+            package_id: package_id.to_string(),
+            version: release.version.to_string(),
+            payload: target.payload.clone(),
+            checksum,
+            release,
+            target,
+        }))
+    }
+
     /// Download package
+    ///
+    /// Streams the payload if it is an artifact stored by this server, and
+    /// redirects to the payload URL otherwise.
     #[oai(path = "/:repo_id/download/:package_id", method = "get")]
     async fn download(
         &self,
         config: Data<&Config>,
-        repo_id: Path<String>,
-        package_id: Path<String>,
-        params: poem::web::Query<PackageKeyParams>,
-    ) -> Result<Response<Binary<String>>> {
-        if !config.repos.contains(&repo_id) {
+        repo_id: Path<RepoId>,
+        package_id: Path<PackageId>,
+        params: poem::web::Query<ReleaseParams>,
+        req: &Request,
+    ) -> Result<Response<Binary<Body>>> {
+        if !repo_id.is_hosted(&config) {
             return Err(NotFoundError.into());
         }
 
-        let platform = match params.0.platform {
-            Some(v) => v,
-            None => {
-                return Err(BadRequest(MissingQueryParamPlatformError));
-            }
-        };
-
-        let guard = GIT_REPO.get().unwrap().read();
+        let (release, target) =
+            find_latest_release(repo_id.as_str(), package_id.as_str(), &params.0)?;
+
+        if !is_resumed_download(req) {
+            DOWNLOAD_STATS.get().unwrap().record(DownloadEvent {
+                timestamp: Utc::now(),
+                repo_id: repo_id.to_string(),
+                package_id: package_id.to_string(),
+                version: release.version.to_string(),
+                platform: target.platform.clone(),
+                channel: release.channel.as_deref().unwrap_or("stable").to_string(),
+            });
+        }
 
-        let index = std::fs::read_to_string(
-            guard
-                .path
-                .join(&repo_id.0)
-                .join("packages")
-                .join(&package_id.0)
-                .join("index.toml"),
-        )
-        .map_err(InternalServerError)?;
-        let descriptor: Descriptor = ::toml::from_str(&index).map_err(InternalServerError)?;
+        let url = target.payload.url();
+        let hosted = artifacts::hosted_file_name(&config, url.as_str()).and_then(|name| {
+            artifacts::local_path(&config, name).map(|path| (name.to_string(), path))
+        });
+        if let Some((file_name, path)) = hosted {
+            return serve_artifact(&config, req, &path, &file_name).await;
+        }
 
-        for release in descriptor.release {
-            if release.channel.as_deref().unwrap_or("stable")
-                != params.0.channel.as_deref().unwrap_or("stable")
-            {
-                continue;
-            }
+        Ok(Response::new(Binary(Body::empty()))
+            .status(StatusCode::TEMPORARY_REDIRECT)
+            .header("Location", url.as_str()))
+    }
 
-            let target = release.target.iter().find(|x| x.platform == platform);
-            if let Some(target) = target {
-                let url = target.payload.url();
-                return Ok(Response::new(Binary("".into()))
-                    .status(StatusCode::TEMPORARY_REDIRECT)
-                    .header("Location", url.as_str()));
-            }
+    /// Get download statistics
+    ///
+    /// Downloads served by `download`, counted per UTC day, version, platform
+    /// and channel. `since` is an inclusive day.
+    #[oai(path = "/:repo_id/packages/:package_id/stats", method = "get")]
+    async fn download_stats(
+        &self,
+        _auth: BearerTokenAuth,
+        config: Data<&Config>,
+        repo_id: Path<RepoId>,
+        package_id: Path<PackageId>,
+        since: Query<Option<NaiveDate>>,
+    ) -> Result<Json<PackageDownloads>> {
+        if !repo_id.is_hosted(&config) {
+            return Err(NotFoundError.into());
         }
 
-        Err(NotFoundError.into())
+        Ok(Json(DOWNLOAD_STATS.get().unwrap().package(
+            repo_id.as_str(),
+            package_id.as_str(),
+            since.0,
+        )))
+    }
+
+    /// Get release feed (Atom)
+    ///
+    /// Recently published releases, optionally limited to one package or
+    /// channel. Names are localized using `lang`, falling back to English.
+    #[oai(path = "/:repo_id/feed.atom", method = "get")]
+    async fn feed_atom(
+        &self,
+        config: Data<&Config>,
+        repo_id: Path<RepoId>,
+        package: Query<Option<String>>,
+        channel: Query<Option<String>>,
+        lang: Query<Option<String>>,
+    ) -> Result<Atom<String>> {
+        let filter = FeedFilter {
+            package_id: package.0.as_deref(),
+            channel: channel.0.as_deref(),
+            lang: lang.0.as_deref(),
+        };
+        let entries = feed_entries(&config, repo_id.as_str(), &filter).await?;
+        let self_url = format!("{}/{}/feed.atom", config.url, repo_id.as_str());
+
+        Ok(Atom(feed::atom(
+            &config,
+            repo_id.as_str(),
+            &self_url,
+            &entries,
+        )))
+    }
+
+    /// Get release feed (JSON Feed)
+    ///
+    /// The same entries as `feed.atom`, in JSON Feed 1.1 format.
+    #[oai(path = "/:repo_id/feed.json", method = "get")]
+    async fn feed_json(
+        &self,
+        config: Data<&Config>,
+        repo_id: Path<RepoId>,
+        package: Query<Option<String>>,
+        channel: Query<Option<String>>,
+        lang: Query<Option<String>>,
+    ) -> Result<Json<JsonFeed>> {
+        let filter = FeedFilter {
+            package_id: package.0.as_deref(),
+            channel: channel.0.as_deref(),
+            lang: lang.0.as_deref(),
+        };
+        let entries = feed_entries(&config, repo_id.as_str(), &filter).await?;
+        let self_url = format!("{}/{}/feed.json", config.url, repo_id.as_str());
+
+        Ok(Json(feed::json_feed(
+            &config,
+            repo_id.as_str(),
+            &self_url,
+            &entries,
+        )))
     }
 
     /// Get package descriptor
@@ -394,19 +803,17 @@ impl Api {
     async fn package_descriptor(
         &self,
         config: Data<&Config>,
-        repo_id: Path<String>,
-        package_id: Path<String>,
-    ) -> Result<Toml<String>> {
-        let path = config
-            .git_path
-            .join(&repo_id.0)
-            .join("packages")
-            .join(package_id.0)
-            .join("index.toml");
-
-        let output = std::fs::read_to_string(path).map_err(|_| poem::Error::from(NotFoundError))?;
-
-        Ok(Toml(output))
+        repo_id: Path<RepoId>,
+        package_id: Path<PackageId>,
+        req: &Request,
+    ) -> Result<Response<Toml<String>>> {
+        let state = snapshot(repo_id.as_str())?;
+        let output = state
+            .descriptors
+            .get(package_id.as_str())
+            .ok_or(NotFoundError)?;
+
+        cached_toml(&config, req, &state, output)
     }
 
     /// Get i18n strings
@@ -416,44 +823,156 @@ impl Api {
     async fn strings(
         &self,
         config: Data<&Config>,
-        repo_id: Path<String>,
-        lang: Path<String>,
-    ) -> Result<Toml<String>> {
-        let lang = lang
-            .0
-            .strip_suffix(".toml")
-            .ok_or_else(|| poem::Error::from(NotFoundError))?;
+        repo_id: Path<RepoId>,
+        lang: Path<LangTag>,
+        req: &Request,
+    ) -> Result<Response<Toml<String>>> {
+        let state = snapshot(repo_id.as_str())?;
+        let output = state.strings.get(lang.tag()).ok_or(NotFoundError)?;
+
+        cached_toml(&config, req, &state, output)
+    }
 
-        let lang = if lang.is_empty() { "en" } else { lang };
+    /// Get repository toml index
+    #[oai(path = "/:repo_id/index.toml", method = "get")]
+    async fn repository_index_toml(
+        &self,
+        config: Data<&Config>,
+        repo_id: Path<RepoId>,
+        req: &Request,
+    ) -> Result<Response<Toml<String>>> {
+        let state = snapshot(repo_id.as_str())?;
 
-        let lang_path = config
-            .git_path
-            .join(&repo_id.0)
-            .join("strings")
-            .join(lang)
-            .with_extension("toml");
+        Ok(cached_toml(&config, req, &state, &state.index_toml)?
+            .header(INDEX_REF_HEADER, &*state.head_ref))
+    }
 
-        tracing::debug!("Strings path: {:?}", &lang_path);
+    /// Get repository index (JSON)
+    ///
+    /// The same index as `index.toml`, as of the last index refresh.
+    #[oai(path = "/:repo_id/index.json", method = "get")]
+    async fn repository_index_json(&self, repo_id: Path<RepoId>) -> Result<Json<Index>> {
+        match REPO_INDEXES.get().unwrap().get(repo_id.as_str()) {
+            Some(state) => Ok(Json(state.load().repo_index.as_ref().clone())),
+            None => Err(NotFoundError.into()),
+        }
+    }
+
+    /// Get all package descriptors (JSON)
+    ///
+    /// The packages of `index.bin`, as of the last index refresh, each with
+    /// its recorded payload `checksums`.
+    #[oai(path = "/:repo_id/packages/index.json", method = "get")]
+    async fn repository_packages_json(
+        &self,
+        config: Data<&Config>,
+        repo_id: Path<RepoId>,
+        req: &Request,
+    ) -> Result<Response<Json<Vec<PackageDescriptor>>>> {
+        let state = match REPO_INDEXES.get().unwrap().get(repo_id.as_str()) {
+            Some(v) => v.load_full(),
+            None => return Err(NotFoundError.into()),
+        };
 
-        let output =
-            std::fs::read_to_string(lang_path).map_err(|_| poem::Error::from(NotFoundError))?;
+        // Generated from the index alone, so the commit identifies the content.
+        let validators =
+            Validators::with_etag(format!("\"{}\"", state.head_ref), Some(state.last_modified));
+        if validators.is_not_modified(req) {
+            return Ok(cache::not_modified(&config, &validators, Json(vec![])));
+        }
 
-        Ok(Toml(output))
+        let packages = state
+            .packages
+            .iter()
+            .filter_map(|p| match p {
+                pahkat_types::package::Package::Concrete(v) => {
+                    Some(PackageDescriptor::new(&state, v))
+                }
+                _ => None,
+            })
+            .collect();
+
+        Ok(cache::cached(&config, &validators, Json(packages)))
     }
 
-    /// Get repository toml index
-    #[oai(path = "/:repo_id/index.toml", method = "get")]
-    async fn repository_index_toml(
+    /// Get changes to the package index since a commit
+    ///
+    /// `base` is the `head_ref` of the index the client has. If the server no
+    /// longer remembers it, `full` is set and every package is returned as added.
+    #[oai(path = "/:repo_id/packages/delta", method = "get")]
+    async fn package_index_delta(
         &self,
-        config: Data<&Config>,
-        repo_id: Path<String>,
-    ) -> Result<Toml<String>> {
-        let index_path = config.git_path.join(&repo_id.0).join("index.toml");
+        repo_id: Path<RepoId>,
+        base: Query<String>,
+    ) -> Result<Json<IndexDelta>> {
+        let head = match REPO_INDEXES.get().unwrap().get(repo_id.as_str()) {
+            Some(v) => v.load_full(),
+            None => return Err(NotFoundError.into()),
+        };
+
+        let delta = match index_generation(repo_id.as_str(), &base.0) {
+            Some(old) => {
+                IndexDelta::between(&base.0, &head.head_ref, &old.packages, &head.packages)
+            }
+            None => IndexDelta::full(&base.0, &head.head_ref, &head.packages),
+        };
 
-        let output =
-            std::fs::read_to_string(index_path).map_err(|_| poem::Error::from(NotFoundError))?;
+        Ok(Json(delta))
+    }
+
+    /// Get repository binary index signature
+    ///
+    /// A detached signature over the uncompressed `index.bin`. `key_id`
+    /// identifies the signing key among those listed by `keys`. Pass the
+    /// `X-Pahkat-Index-Ref` of a fetched `index.bin` as `ref` to get the
+    /// signature for exactly those bytes; without it the current index is used.
+    #[oai(path = "/:repo_id/packages/index.bin.sig", method = "get")]
+    async fn repository_index_bin_signature(
+        &self,
+        repo_id: Path<RepoId>,
+        #[oai(name = "ref")] index_ref: Query<Option<String>>,
+    ) -> Result<Response<Json<DetachedSignature>>> {
+        let state = signed_snapshot(repo_id.as_str(), index_ref.0.as_deref())?;
+        match state.index_bin_signature.clone() {
+            Some(v) => Ok(Response::new(Json(v)).header(INDEX_REF_HEADER, &*state.head_ref)),
+            None => Err(NotFoundError.into()),
+        }
+    }
+
+    /// Get repository toml index signature
+    ///
+    /// A detached signature over `index.toml`, selected by `ref` the same way
+    /// as for `index.bin.sig`.
+    #[oai(path = "/:repo_id/index.toml.sig", method = "get")]
+    async fn repository_index_toml_signature(
+        &self,
+        repo_id: Path<RepoId>,
+        #[oai(name = "ref")] index_ref: Query<Option<String>>,
+    ) -> Result<Response<Json<DetachedSignature>>> {
+        let state = signed_snapshot(repo_id.as_str(), index_ref.0.as_deref())?;
+        match state.index_toml_signature.clone() {
+            Some(v) => Ok(Response::new(Json(v)).header(INDEX_REF_HEADER, &*state.head_ref)),
+            None => Err(NotFoundError.into()),
+        }
+    }
+
+    /// List index signing keys
+    ///
+    /// The key currently signing indexes, followed by retired keys.
+    #[oai(path = "/:repo_id/keys", method = "get")]
+    async fn signing_keys(
+        &self,
+        config: Data<&Config>,
+        repo_id: Path<RepoId>,
+    ) -> Result<Json<Vec<PublicKeyInfo>>> {
+        if !repo_id.is_hosted(&config) {
+            return Err(NotFoundError.into());
+        }
 
-        Ok(Toml(output))
+        Ok(Json(signing::public_keys(
+            SIGNER.get().and_then(|x| x.as_ref()),
+            &config.retired_public_keys,
+        )))
     }
 
     /// Get repository binary index
@@ -461,37 +980,189 @@ impl Api {
     async fn repository_index_bin(
         &self,
         config: Data<&Config>,
-        repo_id: Path<String>,
+        repo_id: Path<RepoId>,
         #[oai(name = "User-Agent")] user_agent: Header<Option<String>>,
-    ) -> Result<Binary<Vec<u8>>> {
+        req: &Request,
+    ) -> Result<Response<Binary<Bytes>>> {
         let user_agent = user_agent.0.unwrap_or_else(|| "".to_string());
 
         if user_agent == "pahkat-client/0.1.0" {
             tracing::debug!("Detected old pahkat, serving workaround index");
-            if repo_id.0 == "divvun-installer" {
-                let index = DIVVUN_INST_REPO_INDEX.get_or_init(|| {
-                    Arc::from(
-                        generate_010_workaround_index(
-                            &config.0,
-                            &config.git_path.join("divvun-installer"),
-                        )
-                        .unwrap(),
-                    )
-                });
-                return Ok(Binary(index.to_vec()));
+            if repo_id.as_str() == "divvun-installer" {
+                let state = snapshot(repo_id.as_str())?;
+                let mut cached = DIVVUN_INST_REPO_INDEX.lock();
+                let index = match &*cached {
+                    Some((head_ref, index)) if *head_ref == state.head_ref => index.clone(),
+                    _ => {
+                        let index = Bytes::from(
+                            generate_010_workaround_index(&config.0, &state)
+                                .map_err(InternalServerError)?,
+                        );
+                        *cached = Some((state.head_ref.clone(), index.clone()));
+                        index
+                    }
+                };
+                return Ok(Response::new(Binary(index)));
             } else {
-                static EMPTY_REPO_INDEX: Lazy<Arc<[u8]>> =
-                    Lazy::new(|| Arc::from(generate_empty_index().unwrap()));
-                return Ok(Binary(EMPTY_REPO_INDEX.to_vec()));
+                static EMPTY_REPO_INDEX: Lazy<Bytes> =
+                    Lazy::new(|| Bytes::from(generate_empty_index().unwrap()));
+                return Ok(Response::new(Binary(EMPTY_REPO_INDEX.clone())));
             }
         }
 
-        match REPO_INDEXES.get().unwrap().get(&repo_id.0) {
-            Some(state) => {
-                let index = &state.load().package_index;
-                Ok(Binary(index.to_vec()))
+        let state = REPO_INDEXES
+            .get()
+            .unwrap()
+            .get(repo_id.as_str())
+            .ok_or(NotFoundError)?
+            .load_full();
+        let encoding = ContentEncoding::negotiate(
+            req.headers()
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|x| x.to_str().ok()),
+        );
+        let validators = Validators::with_etag(
+            state.package_index.etag(encoding),
+            Some(state.last_modified),
+        );
+
+        let response = if validators.is_not_modified(req) {
+            cache::not_modified(&config, &validators, Binary(Bytes::new()))
+        } else {
+            cache::cached(
+                &config,
+                &validators,
+                Binary(state.package_index.get(encoding).clone()),
+            )
+        };
+        let response = response
+            .header(header::VARY, "Accept-Encoding")
+            .header(INDEX_REF_HEADER, &*state.head_ref);
+
+        Ok(match encoding {
+            ContentEncoding::Identity => response,
+            encoding => response.header(header::CONTENT_ENCODING, encoding.as_str()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{
+        http::{Method, Uri},
+        Endpoint, EndpointExt, Route,
+    };
+    use poem_openapi::OpenApiService;
+
+    use super::*;
+
+    fn app(git_path: &std::path::Path) -> impl Endpoint {
+        Route::new()
+            .nest("/", OpenApiService::new(Api, "test", "1.0"))
+            .data(Config::for_tests(git_path, &["main"]))
+            .data(ServerToken("token".to_string()))
+    }
+
+    /// Sends a request and returns its status and body.
+    async fn send(app: &impl Endpoint, method: Method, uri: &str) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(method.clone())
+            .uri(uri.parse::<Uri>().unwrap())
+            .header(header::AUTHORIZATION, "Bearer token");
+        let req = if method == Method::POST {
+            req.content_type("application/json")
+                .body(r#"{"name": {}, "description": {}, "tags": []}"#)
+        } else {
+            req.finish()
+        };
+
+        let resp = app.get_response(req).await;
+        let status = resp.status();
+        (status, resp.into_body().into_string().await.unwrap())
+    }
+
+    const TRAVERSALS: [&str; 6] = [
+        "%2e%2e",
+        "%2E%2E",
+        "..%2f",
+        "..%2fmain",
+        "..%2F..%2Fetc",
+        "a%2Fb",
+    ];
+
+    async fn assert_rejected(app: &impl Endpoint, method: Method, uri: &str, param: &str) {
+        let (status, body) = send(app, method.clone(), uri).await;
+        assert_eq!(
+            status,
+            StatusCode::BAD_REQUEST,
+            "{} {}: {}",
+            method,
+            uri,
+            body
+        );
+        assert!(
+            body.contains(&format!("failed to parse parameter `{}`", param)),
+            "{} {}: {}",
+            method,
+            uri,
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn encoded_traversals_in_ids_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = app(dir.path());
+
+        for value in TRAVERSALS {
+            let repo_routes = [
+                format!("/{}/index.toml", value),
+                format!("/{}/packages", value),
+                format!("/{}/packages/index.json", value),
+                format!("/{}/feed.atom", value),
+                format!("/{}/strings/en.toml", value),
+                format!("/{}/packages/speller-sme/index.toml", value),
+            ];
+            for uri in repo_routes.iter() {
+                assert_rejected(&app, Method::GET, uri, "repo_id").await;
             }
-            None => Err(NotFoundError.into()),
+
+            let package_routes = [
+                format!("/main/packages/{}/index.toml", value),
+                format!("/main/packages/{}/latest", value),
+                format!("/main/download/{}", value),
+            ];
+            for uri in package_routes.iter() {
+                assert_rejected(&app, Method::GET, uri, "package_id").await;
+            }
+
+            let lang = format!("/main/strings/{}.toml", value);
+            assert_rejected(&app, Method::GET, &lang, "lang").await;
+
+            let artifact = format!("/artifacts/{}", value);
+            assert_rejected(&app, Method::GET, &artifact, "filename").await;
+            assert_rejected(&app, Method::PUT, &artifact, "filename").await;
         }
+
+        // Nothing under the git checkout was touched
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn encoded_traversals_cannot_publish() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = app(dir.path());
+
+        for value in TRAVERSALS {
+            let package = format!("/main/packages/{}", value);
+            assert_rejected(&app, Method::POST, &package, "package_id").await;
+            assert_rejected(&app, Method::PATCH, &package, "package_id").await;
+
+            let repo = format!("/{}/packages/speller-sme", value);
+            assert_rejected(&app, Method::POST, &repo, "repo_id").await;
+            assert_rejected(&app, Method::PATCH, &repo, "repo_id").await;
+        }
+
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
     }
 }
@@ -0,0 +1,428 @@
+use std::{borrow::Cow, path};
+
+use chrono::Utc;
+use pahkat_repomgr::package;
+use pahkat_types::package::Package;
+use poem::{
+    error::{BadRequest, Conflict, InternalServerError, NotFoundError, UnprocessableEntity},
+    http::StatusCode,
+};
+
+use crate::{
+    checksums::{self, ChecksumFile, PayloadChecksum, VerifyError},
+    deps,
+    ids::{InvalidIdError, PackageId, RepoId},
+    openapi::{CreatePackageMetadataRequest, UpdatePackageMetadataRequest},
+    state::GIT_REPO,
+    webhooks::{self, WebhookEvent, WebhookEventKind},
+    Config, DependencyPolicy,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum PackageUpdateError {
+    #[error("Invalid version provided")]
+    VersionError(#[from] pahkat_types::package::version::Error),
+
+    #[error("Repo error: {0}")]
+    RepoError(#[source] package::update::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Package with identifier `{0}` already exists.")]
+pub(crate) struct PackageExistsError(String);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Dependencies not provided by any hosted repo: {}", .0.join(", "))]
+pub(crate) struct UnknownDependenciesError(Vec<String>);
+
+/// Everything that can go wrong when creating or updating a package, shared
+/// by the REST and GraphQL APIs.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum PublishError {
+    #[error("Not found")]
+    NotFound,
+
+    #[error(transparent)]
+    InvalidId(#[from] InvalidIdError),
+
+    #[error(transparent)]
+    PackageExists(#[from] PackageExistsError),
+
+    #[error(transparent)]
+    UnknownDependencies(#[from] UnknownDependenciesError),
+
+    #[error("Invalid package metadata: {0}")]
+    InvalidMetadata(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    #[error(transparent)]
+    Update(#[from] PackageUpdateError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not read committed descriptor: {0}")]
+    Descriptor(#[from] ::toml::de::Error),
+
+    #[error("Payload verification failed: {0}")]
+    Verification(#[from] VerifyError),
+}
+
+impl From<PublishError> for poem::Error {
+    fn from(e: PublishError) -> Self {
+        match e {
+            PublishError::NotFound => NotFoundError.into(),
+            PublishError::InvalidId(e) => BadRequest(e),
+            PublishError::PackageExists(e) => Conflict(e),
+            PublishError::UnknownDependencies(e) => BadRequest(e),
+            e @ PublishError::Verification(_) => UnprocessableEntity(e),
+            e @ PublishError::InvalidMetadata(_) => poem::Error::new(e, StatusCode::BAD_REQUEST),
+            e => InternalServerError(e),
+        }
+    }
+}
+
+fn modify_repo_metadata(
+    path: &path::Path,
+    package_id: &str,
+    release: &UpdatePackageMetadataRequest,
+) -> Result<(), PackageUpdateError> {
+    let version: pahkat_types::package::Version = match release.version.parse() {
+        Ok(v) => v,
+        Err(e) => return Err(PackageUpdateError::VersionError(e)),
+    };
+
+    let inner_req = package::update::Request::builder()
+        .repo_path(path.into())
+        .id(package_id.into())
+        .name(release.name.as_ref().map(|x| Cow::Borrowed(&*x)))
+        .description(release.description.as_ref().map(|x| Cow::Borrowed(&*x)))
+        .version(Cow::Owned(version))
+        .channel(release.channel.as_ref().map(|x| Cow::Borrowed(&**x)))
+        .target(Cow::Borrowed(&release.target))
+        .url(None)
+        .build();
+
+    tracing::info!("Updating package...");
+    match package::update::update(inner_req) {
+        Ok(_) => {}
+        Err(e) => return Err(PackageUpdateError::RepoError(e)),
+    };
+
+    Ok(())
+}
+
+fn read_descriptor(
+    git_path: &path::Path,
+    repo_id: &str,
+    package_id: &str,
+) -> Result<Package, PublishError> {
+    let index = std::fs::read_to_string(
+        git_path
+            .join(repo_id)
+            .join("packages")
+            .join(package_id)
+            .join("index.toml"),
+    )?;
+    Ok(::toml::from_str(&index)?)
+}
+
+/// Rejects ids that are not safe to use as paths in the git checkout, so
+/// GraphQL callers get the same check as the REST path parameters.
+fn check_ids(repo_id: &str, package_id: &str) -> Result<(), PublishError> {
+    RepoId::parse(repo_id)?;
+    PackageId::parse(package_id)?;
+    Ok(())
+}
+
+/// Creates a package, commits and pushes it, and returns the new descriptor.
+pub(crate) fn create_package(
+    config: &Config,
+    repo_id: &str,
+    package_id: &str,
+    data: &CreatePackageMetadataRequest,
+) -> Result<Package, PublishError> {
+    check_ids(repo_id, package_id)?;
+    if !config.repos.iter().any(|x| x == repo_id) {
+        return Err(PublishError::NotFound);
+    }
+
+    let mut guard = GIT_REPO.get().unwrap().write();
+
+    if guard
+        .path
+        .join(repo_id)
+        .join("packages")
+        .join(package_id)
+        .join("index.toml")
+        .exists()
+    {
+        return Err(PackageExistsError(package_id.to_string()).into());
+    }
+
+    guard.cleanup(config)?;
+
+    package::init::init(
+        package::init::Request::builder()
+            .repo_path(guard.path.join(repo_id).into())
+            .id(Cow::Borrowed(package_id))
+            .name(Cow::Borrowed(&data.name))
+            .description(Cow::Borrowed(&data.description))
+            .tags(Cow::Borrowed(&data.tags))
+            .build(),
+    )
+    .map_err(|e| PublishError::InvalidMetadata(Box::new(e)))?;
+
+    guard.add_package_to_index_tree(repo_id, package_id)?;
+    if !guard.commit_create(repo_id, package_id)? {
+        tracing::info!("Package unchanged, nothing to publish");
+        return read_descriptor(&guard.path, repo_id, package_id);
+    }
+    guard.push(config)?;
+
+    webhooks::dispatch(
+        config,
+        WebhookEvent {
+            event: WebhookEventKind::PackageCreated,
+            repo_id: repo_id.to_string(),
+            package_id: package_id.to_string(),
+            version: None,
+            channel: None,
+            platforms: vec![],
+            commit: guard.head_ref.clone(),
+            timestamp: Utc::now(),
+        },
+    );
+
+    read_descriptor(&guard.path, repo_id, package_id)
+}
+
+/// The checksum given with an update, if any.
+fn requested_checksum(
+    data: &UpdatePackageMetadataRequest,
+) -> Result<Option<PayloadChecksum>, PublishError> {
+    let sha256 = match (data.sha256.as_deref(), data.blake3.as_deref()) {
+        (Some(v), _) => v,
+        (None, Some(_)) => {
+            return Err(PublishError::InvalidMetadata(
+                "`blake3` requires `sha256` to be given as well".into(),
+            ))
+        }
+        (None, None) => return Ok(None),
+    };
+
+    let digests = std::iter::once(sha256).chain(data.blake3.as_deref());
+    if !digests.into_iter().all(checksums::is_valid_digest) {
+        return Err(PublishError::InvalidMetadata(
+            "checksums must be 64 hexadecimal digits".into(),
+        ));
+    }
+
+    Ok(Some(PayloadChecksum {
+        version: data.version.clone(),
+        channel: data.channel.clone(),
+        platform: data.target.platform.clone(),
+        arch: data.target.arch.clone(),
+        sha256: sha256.to_lowercase(),
+        blake3: data.blake3.as_deref().map(str::to_lowercase),
+    }))
+}
+
+/// Adds or replaces a release, commits and pushes it, and returns the updated
+/// descriptor.
+///
+/// With `verify_payloads` set, the artifact's size (and a given checksum) is
+/// checked before anything is written. A release updated without a checksum
+/// loses any checksum recorded for it earlier.
+pub(crate) async fn update_package(
+    config: &Config,
+    repo_id: &str,
+    package_id: &str,
+    data: &UpdatePackageMetadataRequest,
+) -> Result<Package, PublishError> {
+    check_ids(repo_id, package_id)?;
+    if !config.repos.iter().any(|x| x == repo_id) {
+        return Err(PublishError::NotFound);
+    }
+
+    let checksum = requested_checksum(data)?;
+    if config.verify_payloads {
+        checksums::verify(config, &data.target, checksum.as_ref()).await?;
+    }
+
+    let mut guard = GIT_REPO.get().unwrap().write();
+    let repo_path = guard.path.join(repo_id);
+
+    if !repo_path
+        .join("packages")
+        .join(package_id)
+        .join("index.toml")
+        .exists()
+    {
+        return Err(PublishError::NotFound);
+    }
+
+    let unknown = deps::with_hosted_repos(config, |repos| {
+        deps::unknown_dependencies(repos, &guard.path, repo_id, &data.target)
+    });
+    if !unknown.is_empty() {
+        match config.dependency_policy {
+            DependencyPolicy::Reject => {
+                return Err(UnknownDependenciesError(unknown).into());
+            }
+            DependencyPolicy::Warn => {
+                tracing::warn!(
+                    "Accepting update of {}/{} with unknown dependencies: {:?}",
+                    repo_id,
+                    package_id,
+                    unknown
+                );
+            }
+        }
+    }
+
+    guard.cleanup(config)?;
+    modify_repo_metadata(&repo_path, package_id, data)?;
+    let package_path = repo_path.join("packages").join(package_id);
+    let mut file = ChecksumFile::read(&package_path)?;
+    match checksum {
+        Some(checksum) => {
+            file.upsert(checksum);
+            file.write(&package_path)?;
+        }
+        None => {
+            if file.remove(&data.version, data.channel.as_deref(), &data.target) {
+                file.write(&package_path)?;
+            }
+        }
+    }
+    guard.add_package_to_index_tree(repo_id, package_id)?;
+    if !guard.commit_update(repo_id, package_id, data)? {
+        tracing::info!("Package unchanged, nothing to publish");
+        return read_descriptor(&guard.path, repo_id, package_id);
+    }
+    guard.push(config)?;
+
+    webhooks::dispatch(
+        config,
+        WebhookEvent {
+            event: WebhookEventKind::PackageUpdated,
+            repo_id: repo_id.to_string(),
+            package_id: package_id.to_string(),
+            version: Some(data.version.clone()),
+            channel: data.channel.clone(),
+            platforms: vec![data.target.platform.clone()],
+            commit: guard.head_ref.clone(),
+            timestamp: Utc::now(),
+        },
+    );
+
+    read_descriptor(&guard.path, repo_id, package_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use pahkat_types::payload::Target;
+
+    use super::*;
+
+    const TARGET: &str = r#"
+platform = "windows"
+
+[dependencies]
+
+[payload]
+type = "WindowsExecutable"
+url = "https://pahkat.uit.no/artifacts/speller-sme_1.2.0_windows.exe"
+product_code = "{6A1F2C8E-2D11-4D0E-9E3B-1B2A3C4D5E6F}"
+size = 1024
+installed_size = 4096
+"#;
+
+    fn config() -> Config {
+        Config::for_tests(path::Path::new("/nonexistent"), &["main"])
+    }
+
+    fn invalid_ids() -> Vec<String> {
+        vec![
+            "..".to_string(),
+            ".".to_string(),
+            "../main".to_string(),
+            "speller/sme".to_string(),
+            ".hidden".to_string(),
+            "".to_string(),
+            "a".repeat(129),
+        ]
+    }
+
+    fn create_request() -> CreatePackageMetadataRequest {
+        CreatePackageMetadataRequest {
+            name: Default::default(),
+            description: Default::default(),
+            tags: vec![],
+        }
+    }
+
+    fn update_request() -> UpdatePackageMetadataRequest {
+        UpdatePackageMetadataRequest {
+            name: None,
+            description: None,
+            version: "1.0.0".to_string(),
+            channel: None,
+            authors: vec![],
+            license: None,
+            license_url: None,
+            target: ::toml::from_str::<Target>(TARGET).unwrap(),
+            sha256: None,
+            blake3: None,
+        }
+    }
+
+    #[test]
+    fn create_rejects_unsafe_package_ids() {
+        for id in invalid_ids() {
+            let result = create_package(&config(), "main", &id, &create_request());
+            assert!(
+                matches!(result, Err(PublishError::InvalidId(_))),
+                "accepted package id {:?}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn create_rejects_unsafe_repo_ids() {
+        for id in invalid_ids() {
+            let result = create_package(&config(), &id, "speller-sme", &create_request());
+            assert!(
+                matches!(result, Err(PublishError::InvalidId(_))),
+                "accepted repo id {:?}",
+                id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn update_rejects_unsafe_ids() {
+        for id in invalid_ids() {
+            let result = update_package(&config(), "main", &id, &update_request()).await;
+            assert!(
+                matches!(result, Err(PublishError::InvalidId(_))),
+                "accepted package id {:?}",
+                id
+            );
+
+            let result = update_package(&config(), &id, "speller-sme", &update_request()).await;
+            assert!(
+                matches!(result, Err(PublishError::InvalidId(_))),
+                "accepted repo id {:?}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn longest_allowed_id_is_accepted() {
+        assert!(check_ids("main", &"a".repeat(128)).is_ok());
+        assert!(check_ids("main", "speller-sme_1.0").is_ok());
+    }
+}
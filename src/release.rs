@@ -0,0 +1,243 @@
+use std::cmp::Ordering;
+
+use pahkat_types::{
+    package::{version::SemanticVersion, Descriptor, Release, Version},
+    payload::Target,
+};
+
+/// The platform, architecture and channel a release is being selected for.
+///
+/// If `version` is set, only that exact version is considered.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReleaseQuery<'a> {
+    pub platform: &'a str,
+    pub arch: Option<&'a str>,
+    pub channel: Option<&'a str>,
+    pub version: Option<&'a str>,
+}
+
+fn semantic(version: &Version) -> Option<&SemanticVersion> {
+    match version {
+        Version::Semantic(v) => Some(v),
+        _ => None,
+    }
+}
+
+/// Orders versions so that semantic versions always sort above anything else.
+pub(crate) fn cmp_versions(a: &Version, b: &Version) -> Ordering {
+    semantic(a).cmp(&semantic(b))
+}
+
+/// The architectures able to run a build for `arch`, most preferred first.
+///
+/// ARM64 Windows and macOS can both run x86_64 builds under emulation, so
+/// those are offered when no native build exists.
+fn arch_candidates(arch: &str) -> Vec<&str> {
+    let fallbacks: &[&'static str] = match arch {
+        "arm64" | "aarch64" => &["arm64", "aarch64", "x86_64", "amd64", "i686", "x86"],
+        "x86_64" | "amd64" => &["x86_64", "amd64", "i686", "x86"],
+        "i686" | "x86" => &["i686", "x86"],
+        _ => &[],
+    };
+
+    let mut candidates = vec![arch];
+    candidates.extend(fallbacks.iter().copied().filter(|x| *x != arch));
+    candidates
+}
+
+/// The target of `release` for the queried platform with the most preferred
+/// arch in `candidates`. Targets without an arch match any arch, as does
+/// every target when no arch is queried.
+fn best_target<'d>(
+    release: &'d Release,
+    query: &ReleaseQuery<'_>,
+    candidates: &[&str],
+) -> Option<&'d Target> {
+    release
+        .target
+        .iter()
+        .filter(|t| t.platform == query.platform)
+        .filter_map(|t| match (query.arch, t.arch.as_deref()) {
+            (None, _) | (_, None) => Some((0, t)),
+            (Some(_), Some(arch)) => candidates.iter().position(|x| *x == arch).map(|i| (i, t)),
+        })
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, t)| t)
+}
+
+/// Selects the highest version release with a target matching the query.
+///
+/// Within that release, a native build is preferred over a fallback arch;
+/// older releases are only considered when the newest has no usable build.
+/// Ties keep the release that comes first in the descriptor.
+pub(crate) fn select_release<'d>(
+    descriptor: &'d Descriptor,
+    query: &ReleaseQuery<'_>,
+) -> Option<(&'d Release, &'d Target)> {
+    let channel = query.channel.unwrap_or("stable");
+    let candidates = query.arch.map(arch_candidates).unwrap_or_default();
+
+    let mut selected: Option<(&Release, &Target)> = None;
+    for release in descriptor.release.iter() {
+        if release.channel.as_deref().unwrap_or("stable") != channel {
+            continue;
+        }
+
+        if let Some(version) = query.version {
+            if release.version.to_string() != version {
+                continue;
+            }
+        }
+
+        let target = match best_target(release, query, &candidates) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let is_newer = match selected {
+            Some((current, _)) => cmp_versions(&release.version, &current.version).is_gt(),
+            None => true,
+        };
+        if is_newer {
+            selected = Some((release, target));
+        }
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTOR: &str = r#"
+[package]
+id = "divvun-manager"
+tags = []
+
+[name]
+en = "Divvun Manager"
+
+[description]
+en = "Installer"
+
+[[release]]
+version = "1.0.0"
+authors = []
+
+[[release.target]]
+platform = "macos"
+arch = "arm64"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "TarballPackage"
+url = "https://pahkat.uit.no/artifacts/dm_1.0.0_arm64.txz"
+size = 1
+installed_size = 1
+
+[[release]]
+version = "2.0.0"
+authors = []
+
+[[release.target]]
+platform = "macos"
+arch = "x86_64"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "TarballPackage"
+url = "https://pahkat.uit.no/artifacts/dm_2.0.0_x86_64.txz"
+size = 1
+installed_size = 1
+
+[[release.target]]
+platform = "macos"
+arch = "arm64"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "TarballPackage"
+url = "https://pahkat.uit.no/artifacts/dm_2.0.0_arm64.txz"
+size = 1
+installed_size = 1
+
+[[release]]
+version = "3.0.0"
+authors = []
+
+[[release.target]]
+platform = "macos"
+arch = "x86_64"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "TarballPackage"
+url = "https://pahkat.uit.no/artifacts/dm_3.0.0_x86_64.txz"
+size = 1
+installed_size = 1
+
+[[release]]
+version = "4.0.0"
+authors = []
+
+[[release.target]]
+platform = "macos"
+arch = "riscv64"
+
+[release.target.dependencies]
+
+[release.target.payload]
+type = "TarballPackage"
+url = "https://pahkat.uit.no/artifacts/dm_4.0.0_riscv64.txz"
+size = 1
+installed_size = 1
+"#;
+
+    fn select(arch: Option<&str>, version: Option<&str>) -> Option<(String, Option<String>)> {
+        let descriptor: Descriptor = ::toml::from_str(DESCRIPTOR).unwrap();
+        let query = ReleaseQuery {
+            platform: "macos",
+            arch,
+            channel: None,
+            version,
+        };
+        select_release(&descriptor, &query)
+            .map(|(release, target)| (release.version.to_string(), target.arch.clone()))
+    }
+
+    #[test]
+    fn newest_release_wins_over_older_native_build() {
+        assert_eq!(
+            select(Some("arm64"), None),
+            Some(("3.0.0".to_string(), Some("x86_64".to_string())))
+        );
+    }
+
+    #[test]
+    fn native_build_is_preferred_within_a_release() {
+        assert_eq!(
+            select(Some("arm64"), Some("2.0.0")),
+            Some(("2.0.0".to_string(), Some("arm64".to_string())))
+        );
+    }
+
+    #[test]
+    fn release_without_usable_build_is_skipped() {
+        assert_eq!(
+            select(Some("riscv64"), None),
+            Some(("4.0.0".to_string(), Some("riscv64".to_string())))
+        );
+        assert_eq!(select(Some("i686"), None), None);
+        assert_eq!(select(Some("x86_64"), Some("1.0.0")), None);
+    }
+
+    #[test]
+    fn no_arch_picks_newest() {
+        assert_eq!(select(None, None).map(|x| x.0), Some("4.0.0".to_string()));
+    }
+}
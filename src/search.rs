@@ -0,0 +1,153 @@
+use pahkat_types::{
+    package::{Descriptor, Package},
+    LangTagMap,
+};
+
+/// Criteria for narrowing down the packages of a repo.
+///
+/// Every criterion that is set must match; an empty filter matches everything.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PackageFilter {
+    pub tag: Option<String>,
+    pub platform: Option<String>,
+    pub channel: Option<String>,
+    pub query: Option<String>,
+}
+
+impl PackageFilter {
+    fn matches_release(&self, descriptor: &Descriptor) -> bool {
+        if self.platform.is_none() && self.channel.is_none() {
+            return true;
+        }
+
+        descriptor.release.iter().any(|release| {
+            let channel_matches = match self.channel.as_deref() {
+                Some(channel) => release.channel.as_deref().unwrap_or("stable") == channel,
+                None => true,
+            };
+            let platform_matches = match self.platform.as_deref() {
+                Some(platform) => release.target.iter().any(|t| t.platform == platform),
+                None => true,
+            };
+            channel_matches && platform_matches
+        })
+    }
+
+    fn matches_query(&self, descriptor: &Descriptor) -> bool {
+        let query = match self.query.as_deref() {
+            Some(v) if !v.trim().is_empty() => v.trim().to_lowercase(),
+            _ => return true,
+        };
+
+        std::iter::once(descriptor.package.id.as_str())
+            .chain(descriptor.name.values().map(|x| x.as_str()))
+            .chain(descriptor.description.values().map(|x| x.as_str()))
+            .any(|text| text.to_lowercase().contains(&query))
+    }
+
+    pub fn matches(&self, package: &Package) -> bool {
+        let descriptor = match package {
+            Package::Concrete(v) => v,
+            _ => return false,
+        };
+
+        if let Some(tag) = self.tag.as_deref() {
+            if !descriptor.package.tags.iter().any(|t| t == tag) {
+                return false;
+            }
+        }
+
+        self.matches_release(descriptor) && self.matches_query(descriptor)
+    }
+}
+
+/// A ranked search across the text and metadata of packages.
+///
+/// Every term of `text` must appear in the package id, a localized name or
+/// description, or a tag. The lists narrow the results down; an empty list
+/// does not restrict anything.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SearchQuery {
+    pub text: String,
+    pub platforms: Vec<String>,
+    pub tags: Vec<String>,
+    /// Only names and descriptions in these languages are searched
+    pub languages: Vec<String>,
+}
+
+impl SearchQuery {
+    fn localized<'a>(&'a self, map: &'a LangTagMap<String>) -> impl Iterator<Item = String> + 'a {
+        map.iter()
+            .filter(|(lang, _)| self.languages.is_empty() || self.languages.contains(lang))
+            .map(|(_, value)| value.to_lowercase())
+    }
+
+    fn score_term(&self, descriptor: &Descriptor, term: &str) -> u32 {
+        let id = descriptor.package.id.to_lowercase();
+        let names = self.localized(&descriptor.name).collect::<Vec<_>>();
+
+        let mut score = 0;
+        if id == term {
+            score += 100;
+        } else if id.contains(term) {
+            score += 40;
+        }
+        if names.iter().any(|name| name == term) {
+            score += 80;
+        } else if names.iter().any(|name| name.contains(term)) {
+            score += 30;
+        }
+        if descriptor
+            .package
+            .tags
+            .iter()
+            .any(|tag| tag.to_lowercase().contains(term))
+        {
+            score += 20;
+        }
+        if self
+            .localized(&descriptor.description)
+            .any(|description| description.contains(term))
+        {
+            score += 10;
+        }
+        score
+    }
+
+    /// Relevance of `package`, or `None` if it does not match.
+    pub fn score(&self, package: &Package) -> Option<u32> {
+        let descriptor = match package {
+            Package::Concrete(v) => v,
+            _ => return None,
+        };
+
+        if !self
+            .tags
+            .iter()
+            .all(|tag| descriptor.package.tags.contains(tag))
+        {
+            return None;
+        }
+
+        if !self.platforms.is_empty()
+            && !descriptor.release.iter().any(|release| {
+                release
+                    .target
+                    .iter()
+                    .any(|t| self.platforms.contains(&t.platform))
+            })
+        {
+            return None;
+        }
+
+        let text = self.text.to_lowercase();
+        let mut total = 0;
+        for term in text.split_whitespace() {
+            match self.score_term(descriptor, term) {
+                0 => return None,
+                score => total += score,
+            }
+        }
+        Some(total)
+    }
+}
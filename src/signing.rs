@@ -0,0 +1,238 @@
+use std::{borrow::Cow, path::Path};
+
+use async_graphql::SimpleObject;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use poem_openapi::Object;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+const ALGORITHM: &str = "ed25519";
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum SigningError {
+    #[error("Could not read key: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Key is not valid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+
+    #[error("Invalid ed25519 key: {0}")]
+    Key(#[from] ed25519_dalek::SignatureError),
+
+    #[error("Could not generate random key: {0}")]
+    Random(#[from] getrandom::Error),
+}
+
+/// Identifies a public key: the first 16 hex digits of its SHA-256 hash.
+fn key_id(public: &PublicKey) -> String {
+    hex::encode(&Sha256::digest(public.as_bytes())[..8])
+}
+
+/// A detached signature over a served file, as returned by the `.sig` endpoints.
+#[derive(Debug, Clone, Serialize, Object, SimpleObject)]
+pub(crate) struct DetachedSignature {
+    key_id: String,
+    algorithm: String,
+    /// Base64 encoded signature
+    signature: String,
+}
+
+#[derive(Debug, Clone, Serialize, Object, SimpleObject)]
+pub(crate) struct PublicKeyInfo {
+    key_id: String,
+    algorithm: String,
+    /// Base64 encoded public key
+    public_key: String,
+    /// `current` for the key signing new indexes, `retired` for older keys
+    /// that clients may still see signatures from
+    status: String,
+}
+
+impl PublicKeyInfo {
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn new(public: &PublicKey, status: &str) -> Self {
+        Self {
+            key_id: key_id(public),
+            algorithm: ALGORITHM.to_string(),
+            public_key: base64::encode(public.as_bytes()),
+            status: status.to_string(),
+        }
+    }
+
+    /// Describes a base64 encoded public key that no longer signs indexes.
+    pub fn retired(public_key: &str) -> Result<Self, SigningError> {
+        let public = PublicKey::from_bytes(&base64::decode(public_key.trim())?)?;
+        Ok(Self::new(&public, "retired"))
+    }
+}
+
+/// Signs generated indexes with the configured key.
+pub(crate) struct IndexSigner {
+    keypair: Keypair,
+    key_id: String,
+}
+
+impl std::fmt::Debug for IndexSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IndexSigner")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl IndexSigner {
+    /// Loads a base64 encoded 32-byte secret key, as written by `generate-key`.
+    pub fn load(path: &Path) -> Result<Self, SigningError> {
+        let encoded = std::fs::read_to_string(path)?;
+        let secret = SecretKey::from_bytes(&base64::decode(encoded.trim())?)?;
+        let public = PublicKey::from(&secret);
+
+        Ok(Self {
+            key_id: key_id(&public),
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    pub fn sign(&self, data: &[u8]) -> DetachedSignature {
+        DetachedSignature {
+            key_id: self.key_id.clone(),
+            algorithm: ALGORITHM.to_string(),
+            signature: base64::encode(self.keypair.sign(data).to_bytes()),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKeyInfo {
+        PublicKeyInfo::new(&self.keypair.public, "current")
+    }
+}
+
+/// Writes a new secret key to `path`, refusing to overwrite an existing file.
+pub(crate) fn generate_key(path: &Path) -> Result<PublicKeyInfo, SigningError> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)?;
+    let secret = SecretKey::from_bytes(&bytes)?;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    std::io::Write::write_all(&mut file, base64::encode(secret.as_bytes()).as_bytes())?;
+
+    Ok(PublicKeyInfo::new(&PublicKey::from(&secret), "current"))
+}
+
+/// The current signing key followed by any retired keys.
+pub(crate) fn public_keys(
+    signer: Option<&IndexSigner>,
+    retired_public_keys: &[String],
+) -> Vec<PublicKeyInfo> {
+    let retired = retired_public_keys
+        .iter()
+        .filter_map(|key| match PublicKeyInfo::retired(key) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                tracing::error!("Ignoring retired public key {:?}: {}", key, e);
+                None
+            }
+        });
+
+    signer
+        .map(|x| x.public_key())
+        .into_iter()
+        .chain(retired)
+        .collect()
+}
+
+/// Adds a `[signing]` table describing `key` to a repo's `index.toml`, so the
+/// active key is published with the repo metadata it signs.
+///
+/// A file that already has a `signing` entry is returned unchanged.
+pub(crate) fn with_public_key<'a>(index_toml: &'a str, key: &PublicKeyInfo) -> Cow<'a, str> {
+    let has_signing = ::toml::from_str::<::toml::Value>(index_toml)
+        .ok()
+        .and_then(|x| x.get("signing").cloned())
+        .is_some();
+    if has_signing {
+        tracing::warn!("index.toml already has a `signing` entry, not adding the public key");
+        return Cow::Borrowed(index_toml);
+    }
+
+    Cow::Owned(format!(
+        "{}\n\n[signing]\nkey_id = \"{}\"\nalgorithm = \"{}\"\npublic_key = \"{}\"\n",
+        index_toml.trim_end(),
+        key.key_id,
+        key.algorithm,
+        key.public_key
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEX_TOML: &str = r#"
+[repository]
+url = "https://pahkat.uit.no/main/"
+channels = ["nightly"]
+
+[name]
+en = "Divvun"
+"#;
+
+    fn key() -> PublicKeyInfo {
+        PublicKeyInfo::new(
+            &PublicKey::from(&SecretKey::from_bytes(&[7u8; 32]).unwrap()),
+            "current",
+        )
+    }
+
+    #[test]
+    fn public_key_is_added_to_index_toml() {
+        let key = key();
+        let output = with_public_key(INDEX_TOML, &key);
+        let value: ::toml::Value = ::toml::from_str(&output).unwrap();
+
+        assert_eq!(value["signing"]["key_id"].as_str(), Some(key.key_id()));
+        assert_eq!(value["signing"]["algorithm"].as_str(), Some(ALGORITHM));
+        assert_eq!(
+            value["signing"]["public_key"].as_str(),
+            Some(key.public_key.as_str())
+        );
+        assert_eq!(
+            value["repository"]["url"].as_str(),
+            Some("https://pahkat.uit.no/main/")
+        );
+    }
+
+    #[test]
+    fn existing_signing_table_is_kept() {
+        let input = format!("{}\n[signing]\nkey_id = \"old\"\n", INDEX_TOML);
+        assert_eq!(with_public_key(&input, &key()), input);
+    }
+
+    #[test]
+    fn signature_verifies_with_published_key() {
+        let signer = IndexSigner {
+            keypair: {
+                let secret = SecretKey::from_bytes(&[7u8; 32]).unwrap();
+                let public = PublicKey::from(&secret);
+                Keypair { secret, public }
+            },
+            key_id: key().key_id,
+        };
+        let signature = signer.sign(INDEX_TOML.as_bytes());
+        assert_eq!(signature.key_id, key().key_id);
+
+        let public = PublicKey::from_bytes(&base64::decode(&key().public_key).unwrap()).unwrap();
+        let bytes = base64::decode(&signature.signature).unwrap();
+        let signature = ed25519_dalek::Signature::try_from(&bytes[..]).unwrap();
+        ed25519_dalek::Verifier::verify(&public, INDEX_TOML.as_bytes(), &signature).unwrap();
+    }
+}
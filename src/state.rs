@@ -1,22 +1,61 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, HashMap, VecDeque},
     sync::Arc,
 };
 
 use arc_swap::ArcSwap;
 use once_cell::sync::{Lazy, OnceCell};
 use parking_lot::RwLock;
+use tokio::sync::broadcast;
 
-use crate::{generate_repo_index, git::GitRepo, Config, RepoIndexData, RepoIndexes};
+use crate::{
+    artifacts::{self, ArtifactStore},
+    delta, generate_repo_index,
+    git::GitRepo,
+    signing::IndexSigner,
+    stats::DownloadStats,
+    Config, RepoIndexData, RepoIndexes,
+};
 
 pub(crate) static REPO_INDEXES: OnceCell<RepoIndexes> = OnceCell::new();
 pub(crate) static GIT_REPO: OnceCell<RwLock<GitRepo>> = OnceCell::new();
+pub(crate) static DOWNLOAD_STATS: OnceCell<DownloadStats> = OnceCell::new();
+/// Signs generated indexes, if a signing key is configured.
+pub(crate) static SIGNER: OnceCell<Option<IndexSigner>> = OnceCell::new();
+/// Where uploaded artifacts are stored, if uploads are enabled.
+pub(crate) static ARTIFACT_STORE: OnceCell<Option<Box<dyn ArtifactStore>>> = OnceCell::new();
 pub(crate) static SERVER_STATUS: Lazy<ArcSwap<ServerStatus>> = Lazy::new(|| {
     ArcSwap::from_pointee(ServerStatus {
         index_ref: Default::default(),
     })
 });
 
+/// How many previous index generations are kept per repo for deltas.
+const INDEX_HISTORY_LENGTH: usize = 16;
+
+/// Previous generations of each repo index, newest last.
+pub(crate) static INDEX_HISTORY: Lazy<RwLock<HashMap<String, VecDeque<Arc<RepoIndexData>>>>> =
+    Lazy::new(Default::default);
+
+/// Notifies subscribers whenever a new repo index changes its packages.
+pub(crate) static INDEX_EVENTS: Lazy<broadcast::Sender<IndexChanged>> =
+    Lazy::new(|| broadcast::channel(64).0);
+
+#[derive(Debug, Clone, async_graphql::SimpleObject)]
+pub struct IndexChanged {
+    repo_id: String,
+    old_head_ref: String,
+    new_head_ref: String,
+    /// Packages that were added, removed or modified
+    changed_package_ids: Vec<String>,
+}
+
+impl IndexChanged {
+    pub fn repo_id(&self) -> &str {
+        &self.repo_id
+    }
+}
+
 #[derive(Debug, Clone, poem_openapi::Object, async_graphql::SimpleObject)]
 pub struct ServerStatus {
     index_ref: BTreeMap<String, String>,
@@ -32,12 +71,63 @@ pub(crate) fn server_status() -> ServerStatus {
     ServerStatus { index_ref }
 }
 
-pub(crate) fn set_repo_indexes(state: &ArcSwap<RepoIndexData>, repo_index_data: RepoIndexData) {
-    state.swap(Arc::new(repo_index_data));
+pub(crate) fn set_repo_indexes(
+    repo_id: &str,
+    state: &ArcSwap<RepoIndexData>,
+    repo_index_data: RepoIndexData,
+) {
+    let new = Arc::new(repo_index_data);
+    let old = state.swap(Arc::clone(&new));
     SERVER_STATUS.store(Arc::new(server_status()));
+
+    {
+        let mut history = INDEX_HISTORY.write();
+        let generations = history.entry(repo_id.to_string()).or_default();
+        generations.push_back(Arc::clone(&old));
+        while generations.len() > INDEX_HISTORY_LENGTH {
+            generations.pop_front();
+        }
+    }
+
+    // Refreshes that leave every package untouched are not worth an event.
+    let changed_package_ids = delta::changed_package_ids(&old.packages, &new.packages);
+    if changed_package_ids.is_empty() {
+        return;
+    }
+
+    // Sending only fails when nobody is subscribed.
+    let _ = INDEX_EVENTS.send(IndexChanged {
+        repo_id: repo_id.to_string(),
+        old_head_ref: old.head_ref.to_string(),
+        new_head_ref: new.head_ref.to_string(),
+        changed_package_ids,
+    });
 }
 
 pub(crate) fn init_repo_indexes(config: &Config) -> Result<(), std::io::Error> {
+    let signer = match config.signing_key_path.as_deref() {
+        Some(path) => {
+            let signer = IndexSigner::load(path)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            tracing::info!("Signing indexes with key {}", signer.public_key().key_id());
+            Some(signer)
+        }
+        None => {
+            tracing::warn!("No signing_key_path configured, indexes will not be signed");
+            None
+        }
+    };
+    SIGNER.set(signer).expect("Could not set signer");
+
+    let artifact_store = artifacts::from_config(config)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    if artifact_store.is_none() {
+        tracing::warn!("No artifact_root or artifact_s3 configured, artifact uploads are disabled");
+    }
+    ARTIFACT_STORE
+        .set(artifact_store)
+        .expect("Could not set artifact store");
+
     let git_repo = GitRepo::new(config.git_path.clone());
     if config.skip_repo_cleanup {
         tracing::warn!("Skipping repo cleanup (due to configuration option)");
@@ -70,5 +160,28 @@ pub(crate) fn init_repo_indexes(config: &Config) -> Result<(), std::io::Error> {
 
     SERVER_STATUS.store(Arc::new(server_status()));
 
+    if config.stats_path.is_none() {
+        tracing::warn!("No stats_path configured, download counts will not be persisted");
+    }
+    DOWNLOAD_STATS
+        .set(DownloadStats::open(config.stats_path.as_deref())?)
+        .expect("Could not set download stats");
+
     Ok(())
 }
+
+/// The index of a repo as it was at `head_ref`, if it is still remembered.
+pub(crate) fn index_generation(repo_id: &str, head_ref: &str) -> Option<Arc<RepoIndexData>> {
+    let current = REPO_INDEXES.get().unwrap().get(repo_id)?.load_full();
+    if &*current.head_ref == head_ref {
+        return Some(current);
+    }
+
+    INDEX_HISTORY
+        .read()
+        .get(repo_id)?
+        .iter()
+        .rev()
+        .find(|x| &*x.head_ref == head_ref)
+        .cloned()
+}
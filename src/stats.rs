@@ -0,0 +1,288 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path,
+};
+
+use async_graphql::SimpleObject;
+use chrono::{DateTime, NaiveDate, Utc};
+use parking_lot::RwLock;
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+/// One download served by `download`, as stored in the stats file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DownloadEvent {
+    pub timestamp: DateTime<Utc>,
+    pub repo_id: String,
+    pub package_id: String,
+    pub version: String,
+    pub platform: String,
+    pub channel: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct BucketKey {
+    repo_id: String,
+    package_id: String,
+    day: NaiveDate,
+    version: String,
+    platform: String,
+    channel: String,
+}
+
+impl From<&DownloadEvent> for BucketKey {
+    fn from(event: &DownloadEvent) -> Self {
+        BucketKey {
+            repo_id: event.repo_id.clone(),
+            package_id: event.package_id.clone(),
+            day: event.timestamp.date_naive(),
+            version: event.version.clone(),
+            platform: event.platform.clone(),
+            channel: event.channel.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Object, SimpleObject)]
+pub(crate) struct DailyDownloads {
+    /// UTC day in `YYYY-MM-DD` format
+    date: String,
+    version: String,
+    platform: String,
+    channel: String,
+    count: u64,
+}
+
+#[derive(Debug, Clone, Object, SimpleObject)]
+pub(crate) struct PackageDownloads {
+    repo_id: String,
+    package_id: String,
+    total: u64,
+    days: Vec<DailyDownloads>,
+}
+
+/// Download counts bucketed per day, backed by an append-only JSON lines file.
+#[derive(Debug)]
+pub(crate) struct DownloadStats {
+    buckets: RwLock<BTreeMap<BucketKey, u64>>,
+    /// Events waiting to be appended to the file by the writer thread
+    writer: Option<mpsc::UnboundedSender<DownloadEvent>>,
+}
+
+/// Appends events to `file` on a dedicated thread, so that downloads never
+/// wait for disk I/O.
+fn spawn_writer(
+    mut file: File,
+    path: path::PathBuf,
+) -> Result<mpsc::UnboundedSender<DownloadEvent>, std::io::Error> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<DownloadEvent>();
+
+    std::thread::Builder::new()
+        .name("download-stats".to_string())
+        .spawn(move || {
+            while let Some(event) = rx.blocking_recv() {
+                let result = serde_json::to_string(&event)
+                    .map_err(std::io::Error::from)
+                    .and_then(|line| writeln!(file, "{}", line));
+                if let Err(e) = result {
+                    tracing::error!(error = ?e, "Could not persist download event to {:?}", &path);
+                }
+            }
+        })?;
+
+    Ok(tx)
+}
+
+impl DownloadStats {
+    /// Loads existing counts from `path` and appends new events to it.
+    ///
+    /// Without a path, counts are only kept in memory.
+    pub fn open(path: Option<&path::Path>) -> Result<Self, std::io::Error> {
+        let mut buckets = BTreeMap::new();
+
+        let path = match path {
+            Some(v) => v,
+            None => {
+                return Ok(Self {
+                    buckets: RwLock::new(buckets),
+                    writer: None,
+                })
+            }
+        };
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (n, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<DownloadEvent>(&line) {
+                    Ok(event) => *buckets.entry(BucketKey::from(&event)).or_insert(0) += 1,
+                    Err(e) => {
+                        tracing::error!("Could not parse line {} of {:?}", n + 1, path);
+                        tracing::error!("{}", e);
+                        tracing::error!("Continuing.");
+                    }
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            buckets: RwLock::new(buckets),
+            writer: Some(spawn_writer(file, path.to_path_buf())?),
+        })
+    }
+
+    pub fn record(&self, event: DownloadEvent) {
+        *self
+            .buckets
+            .write()
+            .entry(BucketKey::from(&event))
+            .or_insert(0) += 1;
+
+        if let Some(writer) = self.writer.as_ref() {
+            if writer.send(event).is_err() {
+                tracing::error!("Download stats writer has stopped, event not persisted");
+            }
+        }
+    }
+
+    pub fn package(
+        &self,
+        repo_id: &str,
+        package_id: &str,
+        since: Option<NaiveDate>,
+    ) -> PackageDownloads {
+        let buckets = self.buckets.read();
+        let days = buckets
+            .iter()
+            .filter(|(k, _)| k.repo_id == repo_id && k.package_id == package_id)
+            .filter(|(k, _)| since.map(|since| k.day >= since).unwrap_or(true))
+            .map(|(k, count)| DailyDownloads {
+                date: k.day.format("%Y-%m-%d").to_string(),
+                version: k.version.clone(),
+                platform: k.platform.clone(),
+                channel: k.channel.clone(),
+                count: *count,
+            })
+            .collect::<Vec<_>>();
+
+        PackageDownloads {
+            repo_id: repo_id.to_string(),
+            package_id: package_id.to_string(),
+            total: days.iter().map(|d| d.count).sum(),
+            days,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn event(day: u32, version: &str) -> DownloadEvent {
+        DownloadEvent {
+            timestamp: Utc.with_ymd_and_hms(2022, 10, day, 12, 0, 0).unwrap(),
+            repo_id: "main".to_string(),
+            package_id: "speller-sme".to_string(),
+            version: version.to_string(),
+            platform: "windows".to_string(),
+            channel: "stable".to_string(),
+        }
+    }
+
+    fn counts(downloads: &PackageDownloads) -> Vec<(&str, &str, u64)> {
+        downloads
+            .days
+            .iter()
+            .map(|d| (d.date.as_str(), d.version.as_str(), d.count))
+            .collect()
+    }
+
+    /// Waits for the writer thread to have appended `lines` events.
+    fn wait_for_lines(path: &path::Path, lines: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let written = std::fs::read_to_string(path)
+                .map(|x| x.lines().filter(|l| !l.trim().is_empty()).count())
+                .unwrap_or(0);
+            if written >= lines {
+                return;
+            }
+            assert!(Instant::now() < deadline, "only {} events written", written);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn buckets_per_day_and_version() {
+        let stats = DownloadStats::open(None).unwrap();
+        stats.record(event(1, "1.0.0"));
+        stats.record(event(1, "1.0.0"));
+        stats.record(event(1, "1.1.0"));
+        stats.record(event(3, "1.1.0"));
+
+        let downloads = stats.package("main", "speller-sme", None);
+        assert_eq!(downloads.total, 4);
+        assert_eq!(
+            counts(&downloads),
+            [
+                ("2022-10-01", "1.0.0", 2),
+                ("2022-10-01", "1.1.0", 1),
+                ("2022-10-03", "1.1.0", 1),
+            ]
+        );
+
+        let since = NaiveDate::from_ymd_opt(2022, 10, 2);
+        let downloads = stats.package("main", "speller-sme", since);
+        assert_eq!(downloads.total, 1);
+        assert_eq!(stats.package("main", "speller-smj", None).total, 0);
+    }
+
+    #[test]
+    fn replays_persisted_events_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.jsonl");
+
+        let stats = DownloadStats::open(Some(&path)).unwrap();
+        stats.record(event(1, "1.0.0"));
+        stats.record(event(1, "1.0.0"));
+        stats.record(event(2, "1.1.0"));
+        drop(stats);
+        wait_for_lines(&path, 3);
+
+        // A torn or corrupt line must not lose the rest of the history
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "{{\"timestamp\":").unwrap();
+        writeln!(file).unwrap();
+        drop(file);
+
+        let stats = DownloadStats::open(Some(&path)).unwrap();
+        assert_eq!(
+            counts(&stats.package("main", "speller-sme", None)),
+            [("2022-10-01", "1.0.0", 2), ("2022-10-02", "1.1.0", 1)]
+        );
+
+        stats.record(event(2, "1.1.0"));
+        drop(stats);
+        wait_for_lines(&path, 5);
+
+        let stats = DownloadStats::open(Some(&path)).unwrap();
+        let downloads = stats.package("main", "speller-sme", None);
+        assert_eq!(downloads.total, 4);
+        assert_eq!(
+            counts(&downloads),
+            [("2022-10-01", "1.0.0", 2), ("2022-10-02", "1.1.0", 2)]
+        );
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    collections::{HashMap, HashSet},
+    panic::{self, AssertUnwindSafe},
+    path::{self, PathBuf},
+};
+
+use fbs::FlatBufferBuilder;
+use pahkat_types::package::{Package, Version};
+use serde::Serialize;
+
+use crate::{
+    decoding,
+    deps::{self, RepoPackages},
+    indexing, Config,
+};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum IssueKind {
+    InvalidRepoIndex,
+    InvalidDescriptor,
+    UnsupportedPackage,
+    IndexBuildFailed,
+    DuplicateVersion,
+    EmptyTarget,
+    UnreachableDependency,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Issue {
+    kind: IssueKind,
+    repo_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    package_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Report {
+    pub valid: bool,
+    repos_checked: usize,
+    packages_checked: usize,
+    issues: Vec<Issue>,
+}
+
+impl Report {
+    /// Exit status of the `validate` command: non-zero if any issue was found.
+    pub fn exit_code(&self) -> i32 {
+        if self.valid {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+struct LoadedRepo {
+    repo_id: String,
+    url: String,
+    packages: Vec<Package>,
+    package_ids: HashMap<String, usize>,
+}
+
+fn load_repo(repo_id: &str, path: &path::Path, issues: &mut Vec<Issue>) -> Option<LoadedRepo> {
+    let index_path = path.join("index.toml");
+    let repo_index = std::fs::read_to_string(&index_path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| ::toml::from_str::<pahkat_types::repo::Index>(&s).map_err(|e| e.to_string()));
+    let repo_index = match repo_index {
+        Ok(v) => v,
+        Err(message) => {
+            issues.push(Issue {
+                kind: IssueKind::InvalidRepoIndex,
+                repo_id: repo_id.to_string(),
+                package_id: None,
+                path: Some(index_path),
+                message,
+            });
+            return None;
+        }
+    };
+
+    let packages_path = path.join("packages");
+    let mut dirs = match std::fs::read_dir(&packages_path) {
+        Ok(v) => v
+            .filter_map(Result::ok)
+            .filter(|x| x.file_type().map(|x| x.is_dir()).unwrap_or(false))
+            .map(|x| x.path())
+            .collect::<Vec<_>>(),
+        // A repo without any packages yet is valid.
+        Err(_) => vec![],
+    };
+    dirs.sort();
+
+    let mut packages = vec![];
+    for dir in dirs {
+        let path = dir.join("index.toml");
+        let package_id = dir.file_name().map(|x| x.to_string_lossy().to_string());
+
+        let package = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| ::toml::from_str::<Package>(&s).map_err(|e| e.to_string()));
+        match package {
+            Ok(v) => packages.push(v),
+            Err(message) => issues.push(Issue {
+                kind: IssueKind::InvalidDescriptor,
+                repo_id: repo_id.to_string(),
+                package_id,
+                path: Some(path),
+                message,
+            }),
+        }
+    }
+
+    let package_ids = packages
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.id().to_string(), i))
+        .collect();
+
+    Some(LoadedRepo {
+        repo_id: repo_id.to_string(),
+        url: repo_index.repository.url.to_string(),
+        packages,
+        package_ids,
+    })
+}
+
+fn check_descriptors(repo: &LoadedRepo, issues: &mut Vec<Issue>) -> bool {
+    let mut buildable = true;
+
+    for package in repo.packages.iter() {
+        let issue = |kind, message| Issue {
+            kind,
+            repo_id: repo.repo_id.clone(),
+            package_id: Some(package.id().to_string()),
+            path: None,
+            message,
+        };
+
+        let descriptor = match package {
+            Package::Concrete(v) => v,
+            _ => {
+                buildable = false;
+                issues.push(issue(
+                    IssueKind::UnsupportedPackage,
+                    "Only concrete package descriptors can be indexed".into(),
+                ));
+                continue;
+            }
+        };
+
+        let mut seen = HashSet::new();
+        for release in descriptor.release.iter() {
+            let channel = release.channel.as_deref().unwrap_or("stable");
+
+            if !matches!(release.version, Version::Semantic(_)) {
+                buildable = false;
+                issues.push(issue(
+                    IssueKind::InvalidDescriptor,
+                    format!("Version `{}` is not a semantic version", release.version),
+                ));
+            }
+
+            if !seen.insert((release.version.to_string(), channel)) {
+                issues.push(issue(
+                    IssueKind::DuplicateVersion,
+                    format!(
+                        "Version `{}` appears more than once in channel `{}`",
+                        release.version, channel
+                    ),
+                ));
+            }
+
+            if release.target.is_empty() {
+                issues.push(issue(
+                    IssueKind::EmptyTarget,
+                    format!("Release `{}` ({}) has no targets", release.version, channel),
+                ));
+            }
+        }
+    }
+
+    buildable
+}
+
+fn check_index_build(repo: &LoadedRepo, issues: &mut Vec<Issue>) {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut builder = FlatBufferBuilder::new();
+        indexing::build_index(&mut builder, &repo.packages)
+            .and_then(|index| decoding::verify_index(&repo.packages, index))
+    }));
+
+    let message = match result {
+        Ok(Ok(())) => return,
+        Ok(Err(e)) => format!("{:#}", e),
+        Err(_) => "Flatbuffer generation panicked".to_string(),
+    };
+
+    issues.push(Issue {
+        kind: IssueKind::IndexBuildFailed,
+        repo_id: repo.repo_id.clone(),
+        package_id: None,
+        path: None,
+        message,
+    });
+}
+
+fn check_dependencies(repo: &LoadedRepo, hosted: &[RepoPackages<'_>], issues: &mut Vec<Issue>) {
+    for package in repo.packages.iter() {
+        let descriptor = match package {
+            Package::Concrete(v) => v,
+            _ => continue,
+        };
+
+        let keys = descriptor
+            .release
+            .iter()
+            .flat_map(|r| r.target.iter())
+            .flat_map(|t| t.dependencies.keys())
+            .map(|k| k.as_str())
+            .collect::<HashSet<_>>();
+
+        for key in keys {
+            if deps::locate(hosted, &repo.repo_id, key).is_none() {
+                issues.push(Issue {
+                    kind: IssueKind::UnreachableDependency,
+                    repo_id: repo.repo_id.clone(),
+                    package_id: Some(package.id().to_string()),
+                    path: None,
+                    message: format!("Dependency `{}` is not hosted by any repo", key),
+                });
+            }
+        }
+    }
+}
+
+/// Lints every hosted repo in the configured git path without touching git.
+pub(crate) fn validate(config: &Config) -> Report {
+    let mut issues = vec![];
+
+    let repos = config
+        .repos
+        .iter()
+        .filter_map(|repo_id| load_repo(repo_id, &config.git_path.join(repo_id), &mut issues))
+        .collect::<Vec<_>>();
+
+    for repo in repos.iter() {
+        if check_descriptors(repo, &mut issues) {
+            check_index_build(repo, &mut issues);
+        }
+    }
+
+    let hosted = repos
+        .iter()
+        .map(|repo| RepoPackages {
+            repo_id: &repo.repo_id,
+            url: &repo.url,
+            aliases: deps::repo_aliases(config, &repo.repo_id),
+            packages: &repo.packages,
+            package_ids: &repo.package_ids,
+        })
+        .collect::<Vec<_>>();
+
+    for repo in repos.iter() {
+        check_dependencies(repo, &hosted, &mut issues);
+    }
+
+    Report {
+        valid: issues.is_empty(),
+        repos_checked: repos.len(),
+        packages_checked: repos.iter().map(|r| r.packages.len()).sum(),
+        issues,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn repo_index(repo_id: &str) -> String {
+        format!(
+            r#"
+[repository]
+url = "https://pahkat.example/{}"
+channels = ["nightly"]
+
+[name]
+en = "Test repo"
+
+[description]
+en = "Test repo"
+"#,
+            repo_id
+        )
+    }
+
+    fn descriptor(id: &str, dependency: Option<&str>) -> String {
+        let dependencies = dependency
+            .map(|x| format!("\"{}\" = \"*\"\n", x))
+            .unwrap_or_default();
+        format!(
+            r#"
+[package]
+id = "{id}"
+tags = []
+
+[name]
+en = "{id}"
+
+[description]
+en = "{id}"
+
+[[release]]
+version = "1.0.0"
+
+[[release.target]]
+platform = "windows"
+
+[release.target.dependencies]
+{dependencies}
+[release.target.payload]
+type = "WindowsExecutable"
+url = "https://pahkat.example/artifacts/{id}.exe"
+product_code = "{{6A1F2C8E-2D11-4D0E-9E3B-1B2A3C4D5E6F}}"
+size = 1024
+installed_size = 4096
+"#,
+            id = id,
+            dependencies = dependencies
+        )
+    }
+
+    fn write_repo(git_path: &Path, repo_id: &str, packages: &[(&str, &str)]) {
+        let repo_path = git_path.join(repo_id);
+        std::fs::create_dir_all(repo_path.join("packages")).unwrap();
+        std::fs::write(repo_path.join("index.toml"), repo_index(repo_id)).unwrap();
+
+        for (id, descriptor) in packages {
+            let package_path = repo_path.join("packages").join(id);
+            std::fs::create_dir_all(&package_path).unwrap();
+            std::fs::write(package_path.join("index.toml"), descriptor).unwrap();
+        }
+    }
+
+    fn kinds(report: &Report) -> Vec<String> {
+        report
+            .issues
+            .iter()
+            .map(|x| {
+                serde_json::to_value(x.kind)
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn good_repo_is_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        write_repo(
+            dir.path(),
+            "main",
+            &[
+                ("windivvun", &descriptor("windivvun", None)),
+                ("speller-sme", &descriptor("speller-sme", Some("windivvun"))),
+            ],
+        );
+
+        let report = validate(&Config::for_tests(dir.path(), &["main"]));
+
+        assert!(report.valid, "{:?}", report.issues);
+        assert_eq!(report.repos_checked, 1);
+        assert_eq!(report.packages_checked, 2);
+        assert_eq!(report.exit_code(), 0);
+    }
+
+    #[test]
+    fn broken_repo_reports_each_issue() {
+        let dir = tempfile::tempdir().unwrap();
+        write_repo(
+            dir.path(),
+            "main",
+            &[
+                ("broken", "[package\nid = "),
+                (
+                    "speller-sme",
+                    &descriptor("speller-sme", Some("missing-package")),
+                ),
+            ],
+        );
+
+        let report = validate(&Config::for_tests(dir.path(), &["main"]));
+
+        assert!(!report.valid);
+        assert_eq!(report.exit_code(), 1);
+        assert_eq!(report.packages_checked, 1);
+        assert_eq!(
+            kinds(&report),
+            vec!["invalid-descriptor", "unreachable-dependency"]
+        );
+
+        let invalid = &report.issues[0];
+        assert_eq!(invalid.package_id.as_deref(), Some("broken"));
+        assert_eq!(
+            invalid.path.as_deref(),
+            Some(dir.path().join("main/packages/broken/index.toml").as_path())
+        );
+
+        let dangling = &report.issues[1];
+        assert_eq!(dangling.package_id.as_deref(), Some("speller-sme"));
+        assert!(dangling.message.contains("missing-package"));
+    }
+
+    #[test]
+    fn missing_repo_index_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let report = validate(&Config::for_tests(dir.path(), &["main"]));
+
+        assert_eq!(kinds(&report), vec!["invalid-repo-index"]);
+        assert_eq!(report.repos_checked, 0);
+        assert_eq!(report.exit_code(), 1);
+    }
+
+    #[test]
+    fn dependencies_across_hosted_repos_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        write_repo(
+            dir.path(),
+            "main",
+            &[(
+                "speller-sme",
+                &descriptor(
+                    "speller-sme",
+                    Some("https://pahkat.example/tools/packages/windivvun"),
+                ),
+            )],
+        );
+        write_repo(
+            dir.path(),
+            "tools",
+            &[("windivvun", &descriptor("windivvun", None))],
+        );
+
+        let report = validate(&Config::for_tests(dir.path(), &["main", "tools"]));
+
+        assert!(report.valid, "{:?}", report.issues);
+        assert_eq!(report.repos_checked, 2);
+    }
+}
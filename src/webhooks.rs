@@ -0,0 +1,421 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::Config;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .user_agent(concat!("pahkat-reposrv/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("Could not build webhook HTTP client")
+});
+
+/// Delay before the first retry; doubled after every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Serializes writes to the dead-letter log across delivery tasks.
+static DEAD_LETTER_LOCK: Mutex<()> = parking_lot::const_mutex(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// URL the event is POSTed to
+    pub url: String,
+
+    /// Shared secret used to sign the payload (HMAC-SHA256)
+    pub secret: String,
+
+    /// How many times to attempt delivery before giving up (default: 5)
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) enum WebhookEventKind {
+    #[serde(rename = "package.created")]
+    PackageCreated,
+    #[serde(rename = "package.updated")]
+    PackageUpdated,
+}
+
+impl WebhookEventKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEventKind::PackageCreated => "package.created",
+            WebhookEventKind::PackageUpdated => "package.updated",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct WebhookEvent {
+    pub event: WebhookEventKind,
+    pub repo_id: String,
+    pub package_id: String,
+    pub version: Option<String>,
+    pub channel: Option<String>,
+    pub platforms: Vec<String>,
+    pub commit: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeadLetter<'a> {
+    delivery_id: Uuid,
+    url: &'a str,
+    attempts: u32,
+    error: String,
+    failed_at: DateTime<Utc>,
+    payload: &'a serde_json::Value,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DeliveryError {
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("Endpoint responded with status {0}")]
+    Status(reqwest::StatusCode),
+}
+
+/// Hex-encoded HMAC-SHA256 of `body`, sent as `X-Pahkat-Signature: sha256=<hex>`.
+pub(crate) fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Makes a single delivery attempt. Any non-2xx response is a failure.
+pub(crate) async fn deliver(
+    client: &reqwest::Client,
+    hook: &WebhookConfig,
+    delivery_id: Uuid,
+    event: WebhookEventKind,
+    body: &[u8],
+) -> Result<(), DeliveryError> {
+    let response = client
+        .post(&hook.url)
+        .header("Content-Type", "application/json")
+        .header("X-Pahkat-Event", event.as_str())
+        .header("X-Pahkat-Delivery", delivery_id.to_string())
+        .header(
+            "X-Pahkat-Signature",
+            format!("sha256={}", sign(&hook.secret, body)),
+        )
+        .body(body.to_vec())
+        .send()
+        .await?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(DeliveryError::Status(response.status()))
+    }
+}
+
+fn write_dead_letter(path: &Path, letter: &DeadLetter<'_>) -> Result<(), std::io::Error> {
+    let line = serde_json::to_string(letter)?;
+    let _guard = DEAD_LETTER_LOCK.lock();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)
+}
+
+async fn deliver_with_retries(
+    client: &reqwest::Client,
+    hook: WebhookConfig,
+    event: WebhookEventKind,
+    payload: Arc<serde_json::Value>,
+    body: Arc<[u8]>,
+    dead_letter_path: Option<PathBuf>,
+    mut backoff: Duration,
+) {
+    let delivery_id = Uuid::new_v4();
+    let max_attempts = hook.max_attempts.max(1);
+
+    let mut attempt = 0;
+    let error = loop {
+        attempt += 1;
+        match deliver(client, &hook, delivery_id, event, &body).await {
+            Ok(()) => {
+                tracing::debug!("Delivered webhook {} to {}", delivery_id, &hook.url);
+                return;
+            }
+            Err(e) if attempt >= max_attempts => break e,
+            Err(e) => {
+                tracing::warn!(error = ?e, "Webhook delivery to {} failed, retrying", &hook.url);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    };
+
+    tracing::error!(error = ?error, "Giving up on webhook delivery to {}", &hook.url);
+
+    let path = match dead_letter_path {
+        Some(v) => v,
+        None => return,
+    };
+    let letter = DeadLetter {
+        delivery_id,
+        url: &hook.url,
+        attempts: attempt,
+        error: error.to_string(),
+        failed_at: Utc::now(),
+        payload: &payload,
+    };
+    if let Err(e) = write_dead_letter(&path, &letter) {
+        tracing::error!(error = ?e, "Could not write webhook dead letter");
+    }
+}
+
+/// Sends `event` to every configured webhook in the background.
+pub(crate) fn dispatch(config: &Config, event: WebhookEvent) {
+    if config.webhooks.is_empty() {
+        return;
+    }
+
+    let payload = match serde_json::to_value(&event) {
+        Ok(v) => Arc::new(v),
+        Err(e) => {
+            tracing::error!(error = ?e, "Could not serialize webhook event");
+            return;
+        }
+    };
+    let body: Arc<[u8]> = Arc::from(payload.to_string().into_bytes());
+
+    for hook in config.webhooks.iter() {
+        tokio::spawn(deliver_with_retries(
+            &CLIENT,
+            hook.clone(),
+            event.event,
+            payload.clone(),
+            body.clone(),
+            config.webhook_dead_letter_path.clone(),
+            INITIAL_BACKOFF,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    struct Received {
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    async fn read_request(socket: &mut TcpStream) -> Received {
+        let mut buf = vec![];
+        let mut chunk = [0u8; 1024];
+
+        let head_end = loop {
+            let n = socket.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the headers were read");
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+
+        let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+        let headers: HashMap<String, String> = head
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                Some((key.trim().to_ascii_lowercase(), value.trim().to_string()))
+            })
+            .collect();
+        let len: usize = headers
+            .get("content-length")
+            .map(|x| x.parse().unwrap())
+            .unwrap_or(0);
+
+        while buf.len() < head_end + len {
+            let n = socket.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed before the body was read");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+
+        Received {
+            headers,
+            body: buf[head_end..head_end + len].to_vec(),
+        }
+    }
+
+    /// Accepts webhook deliveries on a local port, answering each one with
+    /// the next status in `statuses` (200 once they run out).
+    async fn listen(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(vec![]));
+        let log = received.clone();
+
+        tokio::spawn(async move {
+            let mut statuses = statuses.into_iter();
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut socket).await;
+                log.lock().push(request);
+
+                let status = statuses.next().unwrap_or(200);
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                let _ = socket.shutdown().await;
+            }
+        });
+
+        (url, received)
+    }
+
+    fn hook(url: &str, max_attempts: u32) -> WebhookConfig {
+        WebhookConfig {
+            url: url.to_string(),
+            secret: "hunter2".to_string(),
+            max_attempts,
+        }
+    }
+
+    fn payload() -> (Arc<serde_json::Value>, Arc<[u8]>) {
+        let payload = serde_json::json!({ "event": "package.updated", "package_id": "speller" });
+        let body: Arc<[u8]> = Arc::from(payload.to_string().into_bytes());
+        (Arc::new(payload), body)
+    }
+
+    #[test]
+    fn sign_matches_hmac_sha256_test_vector() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn delivery_is_signed() {
+        let (url, received) = listen(vec![]).await;
+        let (_, body) = payload();
+        let delivery_id = Uuid::new_v4();
+
+        deliver(
+            &reqwest::Client::new(),
+            &hook(&url, 1),
+            delivery_id,
+            WebhookEventKind::PackageUpdated,
+            &body,
+        )
+        .await
+        .unwrap();
+
+        let received = received.lock();
+        assert_eq!(received.len(), 1);
+        let request = &received[0];
+        assert_eq!(&*request.body, &*body);
+        assert_eq!(
+            request.headers["x-pahkat-signature"],
+            format!("sha256={}", sign("hunter2", &body))
+        );
+        assert_eq!(request.headers["x-pahkat-event"], "package.updated");
+        assert_eq!(
+            request.headers["x-pahkat-delivery"],
+            delivery_id.to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn non_success_status_is_an_error() {
+        let (url, _) = listen(vec![500]).await;
+        let (_, body) = payload();
+
+        let result = deliver(
+            &reqwest::Client::new(),
+            &hook(&url, 1),
+            Uuid::new_v4(),
+            WebhookEventKind::PackageCreated,
+            &body,
+        )
+        .await;
+
+        assert!(matches!(result, Err(DeliveryError::Status(s)) if s.as_u16() == 500));
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried_then_dead_lettered() {
+        let (url, received) = listen(vec![500, 502, 503]).await;
+        let (payload, body) = payload();
+        let dir = tempfile::tempdir().unwrap();
+        let dead_letters = dir.path().join("dead-letters.jsonl");
+
+        deliver_with_retries(
+            &reqwest::Client::new(),
+            hook(&url, 3),
+            WebhookEventKind::PackageUpdated,
+            payload.clone(),
+            body,
+            Some(dead_letters.clone()),
+            Duration::from_millis(1),
+        )
+        .await;
+
+        assert_eq!(received.lock().len(), 3);
+
+        let log = std::fs::read_to_string(&dead_letters).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let letter: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(letter["url"], url.as_str());
+        assert_eq!(letter["attempts"], 3);
+        assert_eq!(letter["payload"], *payload);
+    }
+
+    #[tokio::test]
+    async fn retry_stops_after_success() {
+        let (url, received) = listen(vec![500]).await;
+        let (payload, body) = payload();
+        let dir = tempfile::tempdir().unwrap();
+        let dead_letters = dir.path().join("dead-letters.jsonl");
+
+        deliver_with_retries(
+            &reqwest::Client::new(),
+            hook(&url, 3),
+            WebhookEventKind::PackageUpdated,
+            payload,
+            body,
+            Some(dead_letters.clone()),
+            Duration::from_millis(1),
+        )
+        .await;
+
+        assert_eq!(received.lock().len(), 2);
+        assert!(!dead_letters.exists());
+    }
+}